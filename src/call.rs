@@ -0,0 +1,91 @@
+use crate::device;
+use crate::opts::CallOpts;
+use crate::output::{Format, Output, Render};
+use electricui_cli::prelude::*;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+
+pub async fn call(
+    opts: CallOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    for id in opts.ids.iter() {
+        let report = call_one(&mut client, id, opts.ack, opts.wait.into()).await?;
+        output.emit(&report)?;
+    }
+
+    Ok(())
+}
+
+/// Invokes a callback, collecting anything the board pushes within `wait`
+pub async fn call_one<T: AsyncRead + AsyncWrite + Unpin, const N: usize>(
+    client: &mut Client<'_, T, N>,
+    id: &OwnedMessageId,
+    ack: bool,
+    wait: Duration,
+) -> Result<CallReport, ClientError> {
+    let mut pushed = if ack {
+        client.call_acked(id).await?
+    } else {
+        client.call(id).await?;
+        Vec::new()
+    };
+
+    // Collect anything else the board pushes as a result of the callback
+    let deadline = Instant::now() + wait;
+    let mut reassembler = Reassembler::default();
+    while let Ok(pkt) = time::timeout_at(deadline, client.recv()).await {
+        let pkt = pkt?;
+        if pkt.internal() {
+            debug!("Ignoring internal packet {pkt}");
+            continue;
+        }
+        match reassembler.push(&pkt) {
+            Ok(Some(v)) => pushed.push(v),
+            Ok(None) => (),
+            Err(e) => warn!("Failed to decode variable from packet {pkt}. {e}"),
+        }
+    }
+
+    Ok(CallReport {
+        id: id.clone(),
+        acknowledged: ack,
+        pushed,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallReport {
+    pub id: OwnedMessageId,
+    pub acknowledged: bool,
+    pub pushed: Vec<Variable>,
+}
+
+impl Render for CallReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        if self.acknowledged {
+            writeln!(w, "Called '{}', acknowledged", self.id)?;
+        } else {
+            writeln!(w, "Called '{}'", self.id)?;
+        }
+        for var in self.pushed.iter() {
+            writeln!(w, "  {}", var)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+//! Session capture files, a record of exactly what crossed the wire.
+//!
+//! # Format, version 1
+//!
+//! All integers are little-endian. A capture starts with an 8 byte header:
+//!
+//! | Size | Field                    |
+//! |------|--------------------------|
+//! | 6    | Magic, `EUICAP`          |
+//! | 2    | Format version, `1`      |
+//!
+//! followed by any number of records, until the end of the file:
+//!
+//! | Size | Field                                                         |
+//! |------|---------------------------------------------------------------|
+//! | 8    | Monotonic timestamp, nanoseconds since the capture started    |
+//! | 1    | Direction, `0` host to device (tx), `1` device to host (rx)   |
+//! | 4    | Frame length `F`                                              |
+//! | F    | Raw framed (COBS encoded) bytes                               |
+//! | 2    | Error length `E`, zero when the frame decoded successfully   |
+//! | E    | UTF-8 decoder error message                                   |
+
+use crate::codec::{Decoder, DecoderError};
+use crate::offset::MAX_OFFSET_PACKET_SIZE;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
+use electricui_embedded::prelude::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{fmt, string, thread};
+use thiserror::Error;
+use tracing::warn;
+
+pub const MAGIC: &[u8; 6] = b"EUICAP";
+
+pub const VERSION: u16 = 1;
+
+/// File extension of capture files
+pub const EXTENSION: &str = "euicap";
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Encountered an IO error while reading or writing the capture")]
+    Io(#[from] io::Error),
+
+    #[error("Not a capture file, missing the EUICAP header")]
+    InvalidMagic,
+
+    #[error("Capture format version {0} is not supported, expected version {VERSION}")]
+    UnsupportedVersion(u16),
+
+    #[error("Capture record has an invalid direction {0}")]
+    InvalidDirection(u8),
+
+    #[error("Capture record has a non-UTF-8 error message")]
+    Utf8(#[from] string::FromUtf8Error),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Host to device
+    Tx,
+    /// Device to host
+    Rx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Tx => f.write_str("tx"),
+            Direction::Rx => f.write_str("rx"),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "tx" => Ok(Direction::Tx),
+            "rx" => Ok(Direction::Rx),
+            _ => Err(format!("Invalid direction '{s}', expected tx or rx")),
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Record {
+    /// Time since the capture started
+    pub timestamp: Duration,
+    pub direction: Direction,
+    /// Raw framed bytes
+    pub frame: Vec<u8>,
+    /// Decoder error, if the frame failed to decode
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// Decodes the recorded frame
+    pub fn packet(&self) -> Result<Option<Packet<Bytes>>, DecoderError> {
+        let mut dec_buf = Box::new([0_u8; MAX_OFFSET_PACKET_SIZE]);
+        let mut dec = Decoder::new(&mut dec_buf);
+        let mut src = BytesMut::from(&self.frame[..]);
+        dec.decode_raw(&mut src).1
+    }
+}
+
+/// Writes capture records, timestamped relative to when the writer was created
+pub struct Writer<W: Write> {
+    inner: W,
+    start: Instant,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_u16::<LittleEndian>(VERSION)?;
+        inner.flush()?;
+        Ok(Self {
+            inner,
+            start: Instant::now(),
+        })
+    }
+
+    /// Records a frame with the current timestamp
+    pub fn write(
+        &mut self,
+        direction: Direction,
+        frame: &[u8],
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let timestamp = self.start.elapsed();
+        self.write_record(timestamp, direction, frame, error)
+    }
+
+    pub fn write_record(
+        &mut self,
+        timestamp: Duration,
+        direction: Direction,
+        frame: &[u8],
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let error = error.unwrap_or_default().as_bytes();
+        let error = &error[..error.len().min(u16::MAX.into())];
+        self.inner
+            .write_u64::<LittleEndian>(timestamp.as_nanos() as u64)?;
+        self.inner.write_u8(match direction {
+            Direction::Tx => 0,
+            Direction::Rx => 1,
+        })?;
+        self.inner.write_u32::<LittleEndian>(frame.len() as u32)?;
+        self.inner.write_all(frame)?;
+        self.inner.write_u16::<LittleEndian>(error.len() as u16)?;
+        self.inner.write_all(error)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Records a capture file on a background thread, so recording never blocks the caller,
+/// as used by the [`Codec`](crate::codec::Codec).
+///
+/// Records are flushed whenever the thread catches up, and the remainder when dropped.
+pub struct Recorder {
+    tx: Option<mpsc::Sender<Record>>,
+    thread: Option<thread::JoinHandle<()>>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut writer = Writer::new(BufWriter::new(File::create(path)?))?;
+        let start = writer.start;
+        let (tx, rx) = mpsc::channel::<Record>();
+        let thread = thread::spawn(move || {
+            while let Ok(mut record) = rx.recv() {
+                let res = loop {
+                    if let Err(e) = writer.write_record(
+                        record.timestamp,
+                        record.direction,
+                        &record.frame,
+                        record.error.as_deref(),
+                    ) {
+                        break Err(e);
+                    }
+                    match rx.try_recv() {
+                        Ok(next) => record = next,
+                        Err(_) => break writer.flush(),
+                    }
+                };
+                if let Err(e) = res {
+                    warn!("Stopped recording the session. {e}");
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+            start,
+        })
+    }
+
+    /// Records a frame with the current timestamp
+    pub fn write(
+        &mut self,
+        direction: Direction,
+        frame: &[u8],
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let record = Record {
+            timestamp: self.start.elapsed(),
+            direction,
+            frame: frame.to_vec(),
+            error: error.map(str::to_owned),
+        };
+        self.tx
+            .as_ref()
+            .and_then(|tx| tx.send(record).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Capture writer stopped"))
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread write what's left and exit
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> fmt::Debug for Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Writer")
+            .field("start", &self.start)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reads capture records, checking the header when created
+#[derive(Debug)]
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+        Reader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Self, CaptureError> {
+        let mut magic = [0_u8; MAGIC.len()];
+        inner.read_exact(&mut magic).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => CaptureError::InvalidMagic,
+            _ => e.into(),
+        })?;
+        if &magic != MAGIC {
+            return Err(CaptureError::InvalidMagic);
+        }
+        let version = inner.read_u16::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+        Ok(Self { inner })
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, CaptureError> {
+        let timestamp = match self.inner.read_u64::<LittleEndian>() {
+            Ok(ns) => Duration::from_nanos(ns),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let direction = match self.inner.read_u8()? {
+            0 => Direction::Tx,
+            1 => Direction::Rx,
+            d => return Err(CaptureError::InvalidDirection(d)),
+        };
+        let mut frame = vec![0_u8; self.inner.read_u32::<LittleEndian>()? as usize];
+        self.inner.read_exact(&mut frame)?;
+        let mut error = vec![0_u8; self.inner.read_u16::<LittleEndian>()?.into()];
+        self.inner.read_exact(&mut error)?;
+        let error = if error.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8(error)?)
+        };
+        Ok(Some(Record {
+            timestamp,
+            direction,
+            frame,
+            error,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn round_trip() {
+        let records = vec![
+            Record {
+                timestamp: Duration::from_micros(10),
+                direction: Direction::Tx,
+                frame: vec![0x06, 0x01, 0x44, 0x01, 0x69, 0x3F, 0x00],
+                error: None,
+            },
+            Record {
+                timestamp: Duration::from_millis(3),
+                direction: Direction::Rx,
+                frame: vec![0x00, 0x02, 0x01],
+                error: Some("Invalid checksum".to_owned()),
+            },
+        ];
+        let mut w = Writer::new(Vec::new()).unwrap();
+        for r in records.iter() {
+            w.write_record(r.timestamp, r.direction, &r.frame, r.error.as_deref())
+                .unwrap();
+        }
+
+        let bytes = w.inner;
+        assert_eq!(&bytes[..6], MAGIC);
+        let read: Vec<Record> = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2_u16.to_le_bytes());
+        assert!(matches!(
+            Reader::new(&bytes[..]),
+            Err(CaptureError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Reader::new(&b"EUI"[..]),
+            Err(CaptureError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn recorder_writes_everything_before_dropping() {
+        let path = std::env::temp_dir().join(format!(
+            "electricui-recorder-test-{}.{EXTENSION}",
+            std::process::id()
+        ));
+        let mut recorder = Recorder::create(&path).unwrap();
+        for idx in 0..100_u8 {
+            recorder.write(Direction::Tx, &[idx, 0], None).unwrap();
+        }
+        recorder.write(Direction::Rx, &[1], Some("bad")).unwrap();
+        drop(recorder);
+
+        let read = Reader::open(&path).and_then(|r| r.collect::<Result<Vec<_>, _>>());
+        let _ = std::fs::remove_file(&path);
+        let read = read.unwrap();
+        assert_eq!(read.len(), 101);
+        assert_eq!(read[42].frame, vec![42, 0]);
+        assert!(read.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert_eq!(
+            (read[100].direction, read[100].error.as_deref()),
+            (Direction::Rx, Some("bad"))
+        );
+    }
+}
@@ -1,83 +1,99 @@
-use crate::codec::Codec;
 use crate::device;
 use crate::opts::DeviceOpts;
-use crate::types::*;
-use electricui_embedded::{decoder::Decoder as EUiDecoder, prelude::*};
-use futures::stream::StreamExt;
-use futures::SinkExt;
-use thiserror::Error;
-use tokio_util::codec::Framed;
-use tracing::info;
+use crate::output::{Format, Output, Render};
+use electricui_cli::prelude::*;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::warn;
 
-pub async fn check(opts: DeviceOpts) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let dev = device::new(&opts)?;
-    let mut enc_buf = vec![0_u8; Packet::<&[u8]>::MAX_PACKET_SIZE];
-    let mut dec_buf = Box::new([0_u8; Packet::<&[u8]>::MAX_PACKET_SIZE]);
-    let mut codec = Framed::new(dev, Codec::new(EUiDecoder::new(&mut dec_buf)));
+pub async fn check(
+    opts: DeviceOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dev = device::new(&opts).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(dev, &mut dec_buf, device::link_config(&opts, record)?);
 
-    info!("Requesting board ID");
-    let mut pkt = Packet::new_unchecked(&mut enc_buf);
-    BoardId::encode_request(&mut pkt)?;
-    codec.send(pkt).await?;
+    let board_id = client.board_id().await?;
+    let board_name = client.board_name().await?;
+    let ids = client.writable_ids().await?;
+    let variables = client.tracked_variables().await?;
+    // Everything a schema needs was just requested, refresh the cache for other commands
+    if let Some(cache) = device::schema_cache(&opts) {
+        let schema = BoardSchema::new(board_id, board_name.clone(), &ids, &variables);
+        if let Err(e) = cache.store(&schema) {
+            warn!("Failed to cache the schema of '{board_name}'. {e}");
+        }
+    }
+    let hb = Heartbeat::from(5);
+    let hb_ack = client.heartbeat(hb).await?;
 
-    let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-    let board_id = BoardId::decode_response(&pkt)?;
-    println!("Board ID: 0x{:04X}", board_id);
+    let report = CheckReport {
+        board_id,
+        board_name,
+        ids_count: ids.len(),
+        ids,
+        variables,
+        heartbeat: HeartbeatCheck {
+            sent: hb,
+            received: hb_ack,
+            matches: hb == hb_ack,
+        },
+        heartbeat_monitor: client.heartbeat_stats().copied(),
+    };
+    Output::new(format).emit(&report)?;
 
-    info!("Requesting board name");
-    let mut pkt = Packet::new_unchecked(&mut enc_buf);
-    BoardName::encode_request(&mut pkt)?;
-    codec.send(pkt).await?;
+    Ok(())
+}
 
-    let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-    let board_name = BoardName::decode_response(&pkt)?;
-    println!("Board name: {}", board_name);
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub board_id: BoardId,
+    pub board_name: BoardName,
+    pub ids: IdsAnnouncement,
+    pub ids_count: usize,
+    pub variables: TrackedVariables,
+    pub heartbeat: HeartbeatCheck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_monitor: Option<HeartbeatStats>,
+}
 
-    info!("Requesting writable IDs announcement");
-    let mut pkt = Packet::new_unchecked(&mut enc_buf);
-    WritableIdsAnnouncement::encode_request(&mut pkt)?;
-    codec.send(pkt).await?;
+#[derive(Debug, Serialize)]
+pub struct HeartbeatCheck {
+    pub sent: Heartbeat,
+    pub received: Heartbeat,
+    pub matches: bool,
+}
 
-    // TODO - up to 4 max-len per packet, could be arbitrary num packets
-    // only read 1 for now
-    let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-    let ids = IdsAnnouncement::decode_response(&pkt)?;
-    println!("Message IDs ({}):", ids.len());
-    for id in ids.as_slice().iter() {
-        println!("  {}", id);
+impl Render for CheckReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "Board ID: 0x{:04X}", self.board_id)?;
+        writeln!(w, "Board name: {}", self.board_name)?;
+        writeln!(w, "Message IDs ({}):", self.ids.len())?;
+        for id in self.ids.as_slice().iter() {
+            writeln!(w, "  {}", id)?;
+        }
+        writeln!(w, "IDs count: {}", self.ids_count)?;
+        writeln!(w, "Variables:")?;
+        for var in self.variables.as_slice().iter() {
+            writeln!(w, "  {}", var)?;
+        }
+        writeln!(
+            w,
+            "Heartbeat: {}, matches: {}",
+            self.heartbeat.received, self.heartbeat.matches
+        )?;
+        if let Some(stats) = &self.heartbeat_monitor {
+            writeln!(w, "Heartbeat monitor: {}", stats)?;
+        }
+        Ok(())
     }
-    let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-    let num_ids: usize = WritableIdsAnnouncementEndList::decode_response(&pkt)?.into();
-    println!("IDs count: {}", num_ids);
-
-    info!("Requesting tracked variables");
-    let mut pkt = Packet::new_unchecked(&mut enc_buf);
-    TrackedVariables::encode_request(&mut pkt)?;
-    codec.send(pkt).await?;
+}
 
-    let mut tracked_vars = TrackedVariables::default();
-    for _ in 0..num_ids {
-        let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-        tracked_vars.decode_response_accumulating(&pkt)?;
+impl Render for HeartbeatCheck {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "Heartbeat: {}, matches: {}", self.received, self.matches)
     }
-    println!("Variables:");
-    for var in tracked_vars.as_slice().iter() {
-        println!("  {}", var);
-    }
-
-    let hb = Heartbeat::from(5);
-    info!("Sending heartbeat {hb}");
-    let mut pkt = Packet::new_unchecked(&mut enc_buf);
-    hb.encode_request(&mut pkt)?;
-    codec.send(pkt).await?;
-
-    let pkt = codec.next().await.ok_or(EndOfStreamError)??;
-    let hb_ack = Heartbeat::decode_response(&pkt)?;
-    println!("Heartbeat: {}, matches: {}", hb_ack, hb == hb_ack);
-
-    Ok(())
 }
-
-#[derive(Debug, Error)]
-#[error("Encountered end of stream unexpectedly")]
-pub struct EndOfStreamError;
@@ -0,0 +1,402 @@
+use crate::error::{EndOfStreamError, IdsCountMismatchError, PacketError, PacketProtocolError};
+use crate::heartbeat::{HeartbeatMonitor, HeartbeatStats, MONITOR_HEARTBEATS};
+use crate::link::{self, Link, LinkConfig, LinkError, Response};
+use crate::offset::{self, Reassembler};
+use crate::schema::{BoardSchema, SchemaCache};
+use crate::types::*;
+use bytes::Bytes;
+use electricui_embedded::prelude::*;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Link(#[from] LinkError),
+
+    #[error(transparent)]
+    PacketProtocol(#[from] PacketProtocolError),
+
+    #[error(transparent)]
+    IdsCountMismatch(#[from] IdsCountMismatchError),
+
+    #[error(transparent)]
+    EndOfStream(#[from] EndOfStreamError),
+
+    #[error("Write to '{id}' was not acknowledged after {attempts} attempt(s)")]
+    Unacknowledged { id: OwnedMessageId, attempts: usize },
+
+    #[error(
+        "Heartbeat {0} is reserved for the heartbeat monitor, use a value below {}",
+        MONITOR_HEARTBEATS.start()
+    )]
+    ReservedHeartbeat(Heartbeat),
+}
+
+impl From<PacketError> for ClientError {
+    fn from(e: PacketError) -> Self {
+        PacketProtocolError::from(e).into()
+    }
+}
+
+/// An async client for a device implementing the ElectricUI Binary Protocol,
+/// over any [`Transport`](crate::transport::Transport).
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use electricui_cli::prelude::*;
+///
+/// let dev = tokio_serial::SerialStream::open(&tokio_serial::new("/dev/ttyUSB0", 115200))?;
+/// let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+/// let mut client = Client::new(dev, &mut dec_buf, LinkConfig::default());
+///
+/// println!("Board ID: 0x{:04X}", client.board_id().await?);
+/// let lit_time = client.get(&"lit_time".parse()?).await?;
+/// println!("{lit_time}");
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client<'buf, T, const N: usize> {
+    link: Link<'buf, T, N>,
+    enc_buf: Vec<u8>,
+    writable_ids: Option<IdsAnnouncement>,
+    acknum: u8,
+}
+
+impl<'buf, T: AsyncRead + AsyncWrite + Unpin, const N: usize> Client<'buf, T, N> {
+    pub fn new(dev: T, dec_buf: &'buf mut [u8; N], config: LinkConfig) -> Self {
+        Self {
+            link: Link::new(dev, dec_buf, config),
+            enc_buf: vec![0_u8; MAX_PACKET_SIZE],
+            writable_ids: None,
+            acknum: 0,
+        }
+    }
+
+    pub fn link(&mut self) -> &mut Link<'buf, T, N> {
+        &mut self.link
+    }
+
+    pub fn heartbeat_stats(&self) -> Option<&HeartbeatStats> {
+        self.link.heartbeat_stats()
+    }
+
+    pub async fn board_id(&mut self) -> Result<BoardId, ClientError> {
+        info!("Requesting board ID");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        BoardId::encode_request(&mut pkt)?;
+        let pkt = self
+            .link
+            .request(
+                "board ID",
+                &pkt,
+                true,
+                MessageId::INTERNAL_BOARD_ID.as_bytes(),
+            )
+            .await?;
+        Ok(BoardId::decode_response(&pkt)?)
+    }
+
+    pub async fn board_name(&mut self) -> Result<BoardName, ClientError> {
+        info!("Requesting board name");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        BoardName::encode_request(&mut pkt)?;
+        let pkt = self
+            .link
+            .request("board name", &pkt, false, MessageId::BOARD_NAME.as_bytes())
+            .await?;
+        Ok(BoardName::decode_response(&pkt)?)
+    }
+
+    /// Requests the writable IDs announcement, checking the accumulated IDs
+    /// against the count reported by the board
+    pub async fn writable_ids(&mut self) -> Result<IdsAnnouncement, ClientError> {
+        info!("Requesting writable IDs announcement");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        WritableIdsAnnouncement::encode_request(&mut pkt)?;
+        let pkts = self
+            .link
+            .exchange("writable IDs announcement", &pkt, |p, _| {
+                if link::is_response(p, true, MessageId::INTERNAL_AM_LIST.as_bytes()) {
+                    Response::More
+                } else if link::is_response(p, true, MessageId::INTERNAL_AM_END.as_bytes()) {
+                    Response::Done
+                } else {
+                    Response::Ignore
+                }
+            })
+            .await?;
+
+        let (end, list) = pkts.split_last().ok_or(EndOfStreamError)?;
+        let mut ids = IdsAnnouncement::default();
+        for pkt in list.iter() {
+            ids.decode_response_accumulating(pkt)?;
+        }
+        let end = WritableIdsAnnouncementEndList::decode_response(end)?;
+        ids.check_count(&end)?;
+        self.writable_ids = Some(ids.clone());
+        Ok(ids)
+    }
+
+    /// Returns the board's schema, from `cache` while it is current.
+    ///
+    /// A cached schema is current while the board announces the same number of writable IDs,
+    /// otherwise every tracked variable is requested and the cache refreshed.
+    pub async fn schema(
+        &mut self,
+        cache: Option<&SchemaCache>,
+    ) -> Result<BoardSchema, ClientError> {
+        let board_id = self.board_id().await?;
+        let board_name = self.board_name().await?;
+        let ids = self.writable_ids().await?;
+        if let Some(cache) = cache {
+            match cache.load(board_id, &board_name) {
+                Ok(Some(schema)) if schema.ids_count == ids.len() => {
+                    info!("Using the cached schema of '{board_name}'");
+                    return Ok(schema);
+                }
+                Ok(Some(schema)) => info!(
+                    "Cached schema of '{board_name}' is stale, {} IDs were cached and {} announced",
+                    schema.ids_count,
+                    ids.len()
+                ),
+                Ok(None) => (),
+                Err(e) => warn!("Ignoring the cached schema of '{board_name}'. {e}"),
+            }
+        }
+
+        let variables = self.tracked_variables().await?;
+        let schema = BoardSchema::new(board_id, board_name, &ids, &variables);
+        if let Some(cache) = cache {
+            if let Err(e) = cache.store(&schema) {
+                warn!("Failed to cache the schema of '{}'. {e}", schema.board_name);
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Requests every tracked variable.
+    ///
+    /// The writable IDs announcement is requested first if it hasn't been already,
+    /// since the board doesn't delimit the end of the variables.
+    pub async fn tracked_variables(&mut self) -> Result<TrackedVariables, ClientError> {
+        let num_ids = match self.writable_ids.as_ref() {
+            Some(ids) => ids.len(),
+            None => self.writable_ids().await?.len(),
+        };
+
+        info!("Requesting tracked variables");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        TrackedVariables::encode_request(&mut pkt)?;
+        let mut reassembler = Reassembler::default();
+        let mut vars = Vec::new();
+        let mut error = None;
+        if num_ids != 0 {
+            self.link
+                .exchange("tracked variables", &pkt, |p, received| {
+                    if p.internal() {
+                        return Response::Ignore;
+                    }
+                    if received == 0 {
+                        // Start over on retries
+                        reassembler = Reassembler::default();
+                        vars.clear();
+                    }
+                    match reassembler.push(p) {
+                        Ok(None) => Response::More,
+                        Ok(Some(var)) => {
+                            vars.push(var);
+                            if vars.len() < num_ids {
+                                Response::More
+                            } else {
+                                Response::Done
+                            }
+                        }
+                        Err(e) => {
+                            error = Some(e);
+                            Response::Done
+                        }
+                    }
+                })
+                .await?;
+        }
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(vars.into()),
+        }
+    }
+
+    /// Reads a variable, reassembling it if the board sends it as offset packets
+    pub async fn get(&mut self, id: &OwnedMessageId) -> Result<Variable, ClientError> {
+        info!("Requesting variable '{id}'");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        VariableQuery::encode_request(id, &mut pkt)?;
+        let mut reassembler = Reassembler::default();
+        let mut var = None;
+        self.link
+            .exchange(&format!("'{id}' variable"), &pkt, |p, received| {
+                if !link::is_response(p, false, id.as_bytes()) {
+                    return Response::Ignore;
+                }
+                if received == 0 {
+                    reassembler = Reassembler::default();
+                }
+                match reassembler.push(p) {
+                    Ok(None) => Response::More,
+                    res => {
+                        var = Some(res);
+                        Response::Done
+                    }
+                }
+            })
+            .await?;
+        Ok(var.ok_or(EndOfStreamError)??.ok_or(EndOfStreamError)?)
+    }
+
+    /// Writes a variable, without waiting for any response.
+    ///
+    /// Variables too large for a single packet are written as offset packets.
+    pub async fn set(&mut self, var: &Variable) -> Result<(), ClientError> {
+        info!("Writing variable '{}'", var.id);
+        for buf in var.encode_requests(false, 0)?.iter() {
+            self.link.send(Packet::new_unchecked(&buf[..])).await?;
+        }
+        Ok(())
+    }
+
+    /// Invokes a callback, without waiting for any response
+    pub async fn call(&mut self, id: &OwnedMessageId) -> Result<(), ClientError> {
+        info!("Calling '{id}'");
+        let var = Variable {
+            id: id.clone(),
+            kind: VariableKind::Callback,
+        };
+        self.set(&var).await
+    }
+
+    /// Writes a variable and waits for the board to acknowledge it,
+    /// retransmitting with the same acknum on timeout
+    pub async fn set_acked(&mut self, var: &Variable) -> Result<(), ClientError> {
+        let acknum = self.next_acknum();
+        info!("Writing variable '{}' with acknum {acknum}", var.id);
+        self.acked_exchange(var, acknum).await.map(|_| ())
+    }
+
+    /// Invokes a callback and waits for the board to acknowledge it,
+    /// returning any variables the board pushed before the acknowledgement
+    pub async fn call_acked(&mut self, id: &OwnedMessageId) -> Result<Vec<Variable>, ClientError> {
+        let acknum = self.next_acknum();
+        info!("Calling '{id}' with acknum {acknum}");
+        let var = Variable {
+            id: id.clone(),
+            kind: VariableKind::Callback,
+        };
+        let pkts = self.acked_exchange(&var, acknum).await?;
+        let (_ack, pushed) = pkts.split_last().ok_or(EndOfStreamError)?;
+        let mut reassembler = Reassembler::default();
+        let mut vars = Vec::new();
+        for pkt in pushed.iter() {
+            if let Some(var) = reassembler.push(pkt)? {
+                vars.push(var);
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Sends a heartbeat and returns the board's ack.
+    ///
+    /// Values in [`MONITOR_HEARTBEATS`] are rejected while the heartbeat monitor is enabled,
+    /// their acks would be consumed by the monitor.
+    pub async fn heartbeat(&mut self, hb: Heartbeat) -> Result<Heartbeat, ClientError> {
+        if self.link.heartbeat_stats().is_some() && HeartbeatMonitor::is_monitor_heartbeat(hb) {
+            return Err(ClientError::ReservedHeartbeat(hb));
+        }
+        info!("Sending heartbeat {hb}");
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        hb.encode_request(&mut pkt)?;
+        let pkt = self
+            .link
+            .request(
+                "heartbeat",
+                &pkt,
+                true,
+                MessageId::INTERNAL_HEARTBEAT.as_bytes(),
+            )
+            .await?;
+        Ok(Heartbeat::decode_response(&pkt)?)
+    }
+
+    /// Sends a variable query without waiting for the response,
+    /// see [`Client::recv`]
+    pub async fn send_query(&mut self, id: &OwnedMessageId) -> Result<(), ClientError> {
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        VariableQuery::encode_request(id, &mut pkt)?;
+        self.link.send(pkt).await?;
+        Ok(())
+    }
+
+    /// Sends a tracked variables request without waiting for the responses,
+    /// see [`Client::recv`]
+    pub async fn send_tracked_variables_request(&mut self) -> Result<(), ClientError> {
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        TrackedVariables::encode_request(&mut pkt)?;
+        self.link.send(pkt).await?;
+        Ok(())
+    }
+
+    /// Sends a heartbeat without waiting for the echo,
+    /// see [`Client::recv`]
+    pub async fn send_heartbeat(&mut self, hb: Heartbeat) -> Result<(), ClientError> {
+        let mut pkt = Packet::new_unchecked(&mut self.enc_buf[..]);
+        hb.encode_request(&mut pkt)?;
+        self.link.send(pkt).await?;
+        Ok(())
+    }
+
+    /// Returns the next packet from the device
+    pub async fn recv(&mut self) -> Result<Packet<Bytes>, ClientError> {
+        Ok(self.link.next().await?)
+    }
+
+    /// Acknums roll over 1..=7, zero is reserved for unacknowledged packets
+    fn next_acknum(&mut self) -> u8 {
+        self.acknum = self.acknum % MAX_ACKNUM + 1;
+        self.acknum
+    }
+
+    /// Sends an acknowledged write, returning the non-internal packets received
+    /// up to and including the acknowledgement
+    async fn acked_exchange(
+        &mut self,
+        var: &Variable,
+        acknum: u8,
+    ) -> Result<Vec<Packet<Bytes>>, ClientError> {
+        let id = &var.id;
+        let bufs = var.encode_requests(true, acknum)?;
+        let pkts: Vec<_> = bufs.iter().map(|b| Packet::new_unchecked(&b[..])).collect();
+        let res = self
+            .link
+            .exchange_all(&format!("'{id}' acknowledgement"), &pkts, |p, _| {
+                if link::is_ack(p, id.as_bytes(), acknum) {
+                    Response::Done
+                } else if p.internal() {
+                    Response::Ignore
+                } else {
+                    Response::More
+                }
+            })
+            .await;
+        match res {
+            Ok(pkts) => Ok(pkts),
+            Err(LinkError::Timeout { attempts, .. }) => Err(ClientError::Unacknowledged {
+                id: id.clone(),
+                attempts,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Size of the decoder buffer, large enough for offset packets
+pub const MAX_PACKET_SIZE: usize = offset::MAX_OFFSET_PACKET_SIZE;
@@ -1,9 +1,17 @@
-use bytes::{Buf, Bytes, BytesMut};
+use crate::capture::{Direction, Recorder};
+use crate::client::MAX_PACKET_SIZE;
+use crate::error::PacketProtocolError;
+use crate::offset::{self, OffsetMetadata, Reassembler};
+use crate::types::{OwnedMessageId, VariableKind};
+use bytes::{Bytes, BytesMut};
+use electricui_embedded::message::MessageType;
 use electricui_embedded::{decoder, wire};
+use serde::Serialize;
 use std::io;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio_util::codec;
-use tracing::{debug, warn};
+use tracing::debug;
 
 // TODO - need a std Error impl for the eui types
 #[derive(Debug, Error)]
@@ -21,15 +29,25 @@ pub enum Error {
 pub struct Codec<'buf, const N: usize> {
     dec: Decoder<'buf, N>,
     enc: Encoder,
+    recorder: Option<Recorder>,
+    rx_frame: BytesMut,
 }
 
 impl<'buf, const N: usize> Codec<'buf, N> {
-    pub fn new(dec: decoder::Decoder<'buf, N>) -> Self {
+    pub fn new(dec_buf: &'buf mut [u8; N]) -> Self {
         Self {
-            dec: Decoder::new(dec),
+            dec: Decoder::new(dec_buf),
             enc: Encoder::default(),
+            recorder: None,
+            rx_frame: BytesMut::new(),
         }
     }
+
+    /// Records every frame and decoder error to a capture
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
 }
 
 impl<'buf, const N: usize> codec::Decoder for Codec<'buf, N> {
@@ -40,8 +58,19 @@ impl<'buf, const N: usize> codec::Decoder for Codec<'buf, N> {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let pkt = self.dec.decode(src)?;
-        Ok(pkt)
+        let (raw, res) = self.dec.decode_raw(src);
+        if let Some(recorder) = self.recorder.as_mut() {
+            // Frames can span several reads
+            self.rx_frame.extend_from_slice(&raw);
+            let error = match &res {
+                Ok(None) => return Ok(None),
+                Ok(Some(_)) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            recorder.write(Direction::Rx, &self.rx_frame, error.as_deref())?;
+            self.rx_frame.clear();
+        }
+        Ok(res?)
     }
 }
 
@@ -49,7 +78,11 @@ impl<'buf, const N: usize, T: AsRef<[u8]>> codec::Encoder<wire::Packet<T>> for C
     type Error = Error;
 
     fn encode(&mut self, item: wire::Packet<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
         self.enc.encode(item, dst)?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.write(Direction::Tx, &dst[start..], None)?;
+        }
         Ok(())
     }
 }
@@ -63,52 +96,294 @@ pub enum DecoderError {
     Io(#[from] io::Error),
 }
 
+/// COBS frame decoder.
+///
+/// Unlike [`decoder::Decoder`] this understands offset packets, whose address
+/// bytes sit between the message ID and the payload.
 #[derive(Debug)]
 pub struct Decoder<'buf, const N: usize> {
-    dec: decoder::Decoder<'buf, N>,
+    buf: &'buf mut [u8; N],
+    len: usize,
+    code: u8,
+    remaining: u8,
 }
 
 impl<'buf, const N: usize> Decoder<'buf, N> {
-    pub fn new(dec: decoder::Decoder<'buf, N>) -> Self {
-        Self { dec }
+    pub fn new(buf: &'buf mut [u8; N]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            code: 0,
+            remaining: 0,
+        }
     }
-}
 
-impl<'buf, const N: usize> codec::Decoder for Decoder<'buf, N> {
-    // TODO - figure out the lifetime shenanigans and return borrowed
-    // content from decoder instead of creating a new Bytes packet
-    //type Item = wire::Packet<&'a [u8]>;
-    type Item = wire::Packet<Bytes>;
-    type Error = DecoderError;
+    fn reset(&mut self) {
+        self.len = 0;
+        self.code = 0;
+        self.remaining = 0;
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    /// Returns the length of the frame once it's complete
+    fn push(&mut self, byte: u8) -> Result<Option<usize>, decoder::Error> {
+        if byte == wire::Framing::ZERO {
+            self.reset();
+            return Ok(None);
+        }
+        if self.remaining == 0 {
+            // Every code byte but the first and those following a full block stands in for a zero
+            let implicit_zero = self.code != 0 && self.code != 0xFF;
+            self.code = byte;
+            self.remaining = byte - 1;
+            if implicit_zero {
+                return self.feed(0);
+            }
+            Ok(None)
+        } else {
+            self.remaining -= 1;
+            self.feed(byte)
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Result<Option<usize>, decoder::Error> {
+        if self.len >= self.buf.len() {
+            self.reset();
+            return Err(decoder::Error::InsufficientBufferSize);
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len < wire::Packet::<&[u8]>::HEADER_SIZE {
+            return Ok(None);
+        }
+        let pkt = wire::Packet::new_unchecked(&self.buf[..self.len]);
+        match offset::wire_size(&pkt) {
+            Ok(size) if size == self.len => {
+                let len = self.len;
+                self.reset();
+                Ok(Some(len))
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                self.reset();
+                Err(decoder::Error::PacketError(e.0))
+            }
+        }
+    }
+
+    fn check(&self, len: usize) -> Result<wire::Packet<Bytes>, decoder::Error> {
+        let frame = &self.buf[..len];
+        if wire::Packet::new_unchecked(frame).offset() {
+            offset::check_packet(frame)?;
+        } else {
+            wire::Packet::new(frame)?;
+        }
+        Ok(wire::Packet::new_unchecked(Bytes::copy_from_slice(frame)))
+    }
+
+    /// Decodes the next packet from `src`, also returning the raw bytes consumed.
+    ///
+    /// Bytes up to and including the one that caused a decoder error are consumed,
+    /// so decoding can carry on with the next frame.
+    #[allow(clippy::type_complexity)]
+    pub fn decode_raw(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> (Bytes, Result<Option<wire::Packet<Bytes>>, DecoderError>) {
         for idx in 0..src.len() {
             match self
-                .dec
-                .decode(src[idx])
+                .push(src[idx])
+                .and_then(|frame| frame.map(|len| self.check(len)).transpose())
                 .map_err(crate::error::DecoderError)
             {
                 Err(e) => {
-                    warn!("Discarding {} bytes due to decoder error", idx);
-                    let mut junk = src.split_to(idx);
-                    junk.clear();
-                    // TODO - advance or clear
-                    return Err(e.into());
+                    debug!("Discarding {} bytes due to decoder error", idx + 1);
+                    return (src.split_to(idx + 1).freeze(), Err(e.into()));
                 }
                 Ok(None) => (),
                 Ok(Some(pkt)) => {
                     debug!("Found packet size={}, {}", pkt.as_ref().len(), pkt);
-                    // TODO - advance or clear
-                    src.advance(idx + 1);
-                    return Ok(Some(wire::Packet::new_unchecked(Bytes::copy_from_slice(
-                        pkt.as_ref(),
-                    ))));
+                    return (src.split_to(idx + 1).freeze(), Ok(Some(pkt)));
+                }
+            }
+        }
+        (src.split().freeze(), Ok(None))
+    }
+}
+
+impl<'buf, const N: usize> codec::Decoder for Decoder<'buf, N> {
+    // TODO - figure out the lifetime shenanigans and return borrowed
+    // content from decoder instead of creating a new Bytes packet
+    //type Item = wire::Packet<&'a [u8]>;
+    type Item = wire::Packet<Bytes>;
+    type Error = DecoderError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_raw(src).1
+    }
+}
+
+/// Decodes a byte stream a frame at a time, producing exactly one [`FrameEntry`] per frame,
+/// including frames that fail to decode
+pub struct FrameDecoder<'buf> {
+    dec: Decoder<'buf, MAX_PACKET_SIZE>,
+    reassembler: Reassembler,
+    direction: Option<Direction>,
+    timestamps: bool,
+    frame: BytesMut,
+    pending: Option<FrameEntry>,
+}
+
+impl<'buf> FrameDecoder<'buf> {
+    pub fn new(
+        dec_buf: &'buf mut [u8; MAX_PACKET_SIZE],
+        direction: Option<Direction>,
+        timestamps: bool,
+    ) -> Self {
+        Self {
+            dec: Decoder::new(dec_buf),
+            reassembler: Reassembler::default(),
+            direction,
+            timestamps,
+            frame: BytesMut::new(),
+            pending: None,
+        }
+    }
+
+    /// Consumes `buf`, returning the raw bytes and entry of every delimited frame
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Vec<(Bytes, FrameEntry)> {
+        let mut entries = Vec::new();
+        while !buf.is_empty() {
+            let delimited = buf.iter().position(|b| *b == wire::Framing::ZERO);
+            let mut chunk = buf.split_to(delimited.map_or(buf.len(), |idx| idx + 1));
+            while !chunk.is_empty() {
+                if self.pending.is_some() {
+                    // Anything after a packet or an error belongs to the same frame
+                    self.frame.extend_from_slice(&chunk.split());
+                    break;
+                }
+                let (raw, res) = self.dec.decode_raw(&mut chunk);
+                self.frame.extend_from_slice(&raw);
+                self.pending = match res {
+                    Ok(None) => None,
+                    Ok(Some(pkt)) => Some(self.packet(&pkt)),
+                    Err(e) => Some(self.error(e.to_string())),
+                };
+            }
+            if delimited.is_some() {
+                entries.extend(self.finish());
+            }
+        }
+        entries
+    }
+
+    /// Ends the current frame, reporting it if it held anything but delimiters
+    pub fn finish(&mut self) -> Option<(Bytes, FrameEntry)> {
+        let entry = match self.pending.take() {
+            Some(entry) => Some(entry),
+            None if self.frame.iter().any(|b| *b != wire::Framing::ZERO) => {
+                Some(self.error("Frame ended before a complete packet".to_owned()))
+            }
+            None => None,
+        };
+        let frame = self.frame.split().freeze();
+        entry.map(|e| (frame, e))
+    }
+
+    fn entry(&self) -> FrameEntry {
+        FrameEntry {
+            timestamp: self
+                .timestamps
+                .then(|| humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+            direction: self.direction,
+            ..Default::default()
+        }
+    }
+
+    fn packet(&mut self, pkt: &wire::Packet<Bytes>) -> FrameEntry {
+        debug!("Decoded {pkt}");
+        let mut entry = self.entry();
+        entry.id = pkt
+            .msg_id()
+            .ok()
+            .map(|id| OwnedMessageId::from_wire(&id).to_string());
+        entry.typ = Some(pkt.typ().to_string());
+        entry.internal = pkt.internal();
+        entry.response = pkt.response();
+        entry.acknum = pkt.acknum();
+        entry.data_length = Some(pkt.data_length());
+        // The checksum follows the address of offset packets
+        entry.checksum = offset::wire_size(pkt)
+            .ok()
+            .and_then(|size| pkt.as_ref().get(size.checked_sub(2)?..size))
+            .map(|crc| u16::from_le_bytes([crc[0], crc[1]]));
+        if pkt.offset() {
+            entry.offset = offset::address(pkt).ok();
+        }
+
+        if pkt.offset() || pkt.typ() == MessageType::OffsetMetadata {
+            if let Ok(meta) = OffsetMetadata::decode_response(pkt) {
+                entry.note = Some(format!("offset range {}..{}", meta.base, meta.end));
+            }
+            match self.reassembler.push(pkt) {
+                Ok(Some(var)) => {
+                    entry.note = Some("reassembled".to_owned());
+                    entry.value = Some(var.kind);
                 }
+                Ok(None) => (),
+                Err(e) => entry.error = Some(e.to_string()),
+            }
+        } else {
+            match pkt
+                .payload()
+                .map_err(PacketProtocolError::from)
+                .and_then(|data| VariableKind::from_wire(pkt.typ(), data))
+            {
+                Ok(kind) => entry.value = Some(kind),
+                Err(e) => entry.error = Some(e.to_string()),
             }
         }
-        src.clear();
-        Ok(None)
+        entry
     }
+
+    fn error(&self, error: String) -> FrameEntry {
+        let mut entry = self.entry();
+        entry.note = Some(format!(
+            "{} bytes: {:02X?}",
+            self.frame.len(),
+            &self.frame[..]
+        ));
+        entry.error = Some(error);
+        entry
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+/// A decoded frame, or the reason it failed to decode
+pub struct FrameEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<Direction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    pub internal: bool,
+    pub response: bool,
+    pub acknum: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_length: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u16>,
+    /// Address of an offset packet's payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<VariableKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -127,7 +402,7 @@ impl<T: AsRef<[u8]>> codec::Encoder<wire::Packet<T>> for Encoder {
     type Error = EncoderError;
 
     fn encode(&mut self, item: wire::Packet<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let pkt_size = item.wire_size().map_err(crate::error::PacketError)?;
+        let pkt_size = offset::wire_size(&item)?;
         dst.resize(wire::Framing::max_encoded_len(pkt_size), 0);
         let wire_size = wire::Framing::encode_buf(&item.as_ref()[..pkt_size], dst);
         dst.resize(wire_size, 0);
@@ -135,3 +410,206 @@ impl<T: AsRef<[u8]>> codec::Encoder<wire::Packet<T>> for Encoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{OwnedMessageId, Variable, VariableKind};
+    use pretty_assertions::assert_eq;
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    fn var(id: &[u8], kind: VariableKind) -> Variable {
+        Variable {
+            id: OwnedMessageId::new(id).unwrap(),
+            kind,
+        }
+    }
+
+    fn packet(v: &Variable) -> Vec<u8> {
+        v.encode_requests(false, 0).unwrap().remove(0)
+    }
+
+    fn frame(pkt: &[u8]) -> BytesMut {
+        let mut dst = BytesMut::new();
+        Encoder::default()
+            .encode(wire::Packet::new_unchecked(pkt), &mut dst)
+            .unwrap();
+        dst
+    }
+
+    /// Decodes everything in `src`, keeping errors in place of the packets they replaced
+    fn decode_all(src: &mut BytesMut) -> Vec<Result<Variable, String>> {
+        let mut buf = [0_u8; offset::MAX_OFFSET_PACKET_SIZE];
+        let mut dec = Decoder::new(&mut buf);
+        let mut out = Vec::new();
+        loop {
+            match dec.decode(src) {
+                Ok(Some(p)) => out.push(Ok(Variable::decode_response(&p).unwrap())),
+                Ok(None) => break,
+                Err(e) => out.push(Err(e.to_string())),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn skips_junk_between_frames() {
+        let a = var(b"a", VariableKind::U16(200));
+        let b = var(b"b", VariableKind::I8(-3));
+        let mut src = frame(&packet(&a));
+        src.extend_from_slice(&[0x03, 0x11, 0x22, 0x00]);
+        src.extend_from_slice(&frame(&packet(&b)));
+        assert_eq!(decode_all(&mut src), vec![Ok(a), Ok(b)]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let vars: Vec<Variable> = (0..3)
+            .map(|i| var(b"led", VariableKind::U8(i + 1)))
+            .collect();
+        let mut src = BytesMut::new();
+        for v in vars.iter() {
+            src.extend_from_slice(&frame(&packet(v)));
+        }
+        assert_eq!(
+            decode_all(&mut src),
+            vars.into_iter().map(Ok).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn decodes_full_code_blocks() {
+        // Over 254 non-zero bytes in a row are split into 0xFF code blocks
+        let big = var(b"big", VariableKind::U8Array(vec![0xAB; 600]));
+        let mut src = frame(&packet(&big));
+        assert!(src.contains(&0xFF));
+        assert_eq!(decode_all(&mut src), vec![Ok(big)]);
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let big = var(b"big", VariableKind::U16Array((1..300).collect()));
+        let wire = frame(&packet(&big));
+        let mut buf = [0_u8; offset::MAX_OFFSET_PACKET_SIZE];
+        let mut dec = Decoder::new(&mut buf);
+        let mut src = BytesMut::new();
+        let mut out = Vec::new();
+        for chunk in wire.chunks(7) {
+            src.extend_from_slice(chunk);
+            while let Some(p) = dec.decode(&mut src).unwrap() {
+                out.push(Variable::decode_response(&p).unwrap());
+            }
+        }
+        assert_eq!(out, vec![big]);
+    }
+
+    #[test]
+    fn recovers_after_a_bad_checksum() {
+        let a = var(b"a", VariableKind::U32(0x1122_3344));
+        let b = var(b"b", VariableKind::U32(7));
+        let mut corrupt = packet(&a);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0x5A;
+        let mut src = frame(&corrupt);
+        src.extend_from_slice(&frame(&packet(&b)));
+
+        let out = decode_all(&mut src);
+        assert_eq!(out.len(), 2);
+        assert!(out[0].is_err());
+        assert_eq!(out[1], Ok(b));
+    }
+
+    /// Summarises entries as their ID, value and whether they hold an error
+    fn summary(
+        entries: &[(Bytes, FrameEntry)],
+    ) -> Vec<(Option<String>, Option<VariableKind>, bool)> {
+        entries
+            .iter()
+            .map(|(_, e)| (e.id.clone(), e.value.clone(), e.error.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn frame_decoder_splits_back_to_back_frames() {
+        let mut buf = frame(&packet(&var(b"led", VariableKind::U8(1))));
+        buf.extend_from_slice(&frame(&packet(&var(
+            b"big",
+            VariableKind::U16Array(vec![1, 2]),
+        ))));
+        let wire = buf.clone();
+
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut dec = FrameDecoder::new(&mut dec_buf, None, false);
+        let entries = dec.decode(&mut buf);
+        assert_eq!(
+            summary(&entries),
+            vec![
+                (Some("led".to_owned()), Some(VariableKind::U8(1)), false),
+                (
+                    Some("big".to_owned()),
+                    Some(VariableKind::U16Array(vec![1, 2])),
+                    false
+                ),
+            ]
+        );
+        let json = serde_json::to_value(&entries[0].1).unwrap();
+        assert_eq!(json["type"], "U8");
+        let frames: Vec<u8> = entries.iter().flat_map(|(f, _)| f.to_vec()).collect();
+        assert_eq!(frames, wire.to_vec());
+        assert!(dec.finish().is_none());
+    }
+
+    #[test]
+    fn frame_decoder_finish_reports_the_final_frame() {
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut dec = FrameDecoder::new(&mut dec_buf, None, false);
+
+        // A complete packet missing its delimiter
+        let mut buf = frame(&packet(&var(b"led", VariableKind::U8(1))));
+        buf.truncate(buf.len() - 1);
+        assert!(dec.decode(&mut buf).is_empty());
+        assert_eq!(
+            summary(&dec.finish().into_iter().collect::<Vec<_>>()),
+            vec![(Some("led".to_owned()), Some(VariableKind::U8(1)), false)]
+        );
+
+        // Half a packet
+        let mut buf = frame(&packet(&var(b"led", VariableKind::U32(7))));
+        buf.truncate(5);
+        assert!(dec.decode(&mut buf).is_empty());
+        let (raw, entry) = dec.finish().unwrap();
+        assert_eq!(raw.len(), 5);
+        assert_eq!(
+            entry.error.as_deref(),
+            Some("Frame ended before a complete packet")
+        );
+        assert!(dec.finish().is_none());
+    }
+
+    #[test]
+    fn frame_decoder_error_consumes_the_rest_of_the_frame() {
+        let mut corrupt = packet(&var(b"led", VariableKind::U8(1)));
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0x5A;
+        let mut buf = frame(&corrupt);
+        // Trailing junk before the delimiter belongs to the failed frame
+        let delimiter = buf.len() - 1;
+        buf.truncate(delimiter);
+        buf.extend_from_slice(&[0x01, 0x02, 0x00]);
+        let corrupt_len = buf.len();
+        buf.extend_from_slice(&frame(&packet(&var(b"led", VariableKind::U8(2)))));
+
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut dec = FrameDecoder::new(&mut dec_buf, None, false);
+        let entries = dec.decode(&mut buf);
+        assert_eq!(
+            summary(&entries),
+            vec![
+                (None, None, true),
+                (Some("led".to_owned()), Some(VariableKind::U8(2)), false),
+            ]
+        );
+        assert_eq!(entries[0].0.len(), corrupt_len);
+    }
+}
@@ -0,0 +1,269 @@
+//! Differences between two sets of variables, e.g. two boards or a board and a snapshot.
+
+use crate::schema::VariableSchema;
+use crate::types::float_serde;
+use crate::types::{OwnedMessageId, Variable, VariableKind};
+use serde::Serialize;
+use std::fmt;
+
+/// Numeric difference of a changed value, `b - a`
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Delta {
+    Scalar(#[serde(with = "float_serde::scalar")] f64),
+    /// Element-wise, for arrays of the same length
+    Array(#[serde(with = "float_serde::array")] Vec<f64>),
+    /// Element-wise over the common prefix of arrays of different lengths, with the
+    /// number of elements added (positive) or removed (negative)
+    Resized {
+        #[serde(with = "float_serde::array")]
+        prefix: Vec<f64>,
+        len: isize,
+    },
+}
+
+impl Delta {
+    pub fn between(a: &VariableKind, b: &VariableKind) -> Option<Self> {
+        if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+            return Some(Delta::Scalar(b - a));
+        }
+        let (a, b) = (a.as_f64_array()?, b.as_f64_array()?);
+        let prefix = a.iter().zip(b.iter()).map(|(a, b)| b - a).collect();
+        if a.len() == b.len() {
+            Some(Delta::Array(prefix))
+        } else {
+            Some(Delta::Resized {
+                prefix,
+                len: b.len() as isize - a.len() as isize,
+            })
+        }
+    }
+}
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elements = |d: &[f64]| {
+            let d: Vec<String> = d.iter().map(|d| format!("{d:+}")).collect();
+            format!("[{}]", d.join(", "))
+        };
+        match self {
+            Delta::Scalar(d) => write!(f, "{d:+}"),
+            Delta::Array(d) => f.write_str(&elements(d)),
+            Delta::Resized { prefix, len } if *len > 0 => {
+                write!(f, "{}, {len} added", elements(prefix))
+            }
+            Delta::Resized { prefix, len } => {
+                write!(f, "{}, {} removed", elements(prefix), -len)
+            }
+        }
+    }
+}
+
+/// A difference between the variables of side `a` and side `b`
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum VariableChange {
+    /// A variable only `b` has
+    Added(Variable),
+    /// A variable only `a` has
+    Removed(Variable),
+    /// A variable whose type or shape differs
+    Retyped {
+        id: OwnedMessageId,
+        a: VariableKind,
+        b: VariableKind,
+    },
+    /// A variable of the same type with a different value, or array length
+    Changed {
+        id: OwnedMessageId,
+        a: VariableKind,
+        b: VariableKind,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        delta: Option<Delta>,
+    },
+}
+
+impl VariableChange {
+    pub fn id(&self) -> &OwnedMessageId {
+        match self {
+            VariableChange::Added(var) | VariableChange::Removed(var) => &var.id,
+            VariableChange::Retyped { id, .. } | VariableChange::Changed { id, .. } => id,
+        }
+    }
+}
+
+impl fmt::Display for VariableChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VariableChange::Added(var) => write!(f, "+ {} {}", var.id, var.kind),
+            VariableChange::Removed(var) => write!(f, "- {} {}", var.id, var.kind),
+            VariableChange::Retyped { id, a, b } => write!(f, "~ {id} {a} -> {b}"),
+            VariableChange::Changed { id, a, b, delta } => {
+                write!(f, "* {id} {a} -> {b}")?;
+                if let Some(delta) = delta {
+                    write!(f, " ({delta})")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Differences of `b` from `a`.
+///
+/// Removed, retyped and changed variables are listed in `a`'s order, followed by
+/// added variables in `b`'s order.
+pub fn diff(a: &[Variable], b: &[Variable]) -> Vec<VariableChange> {
+    let find = |vars: &[Variable], id: &OwnedMessageId| vars.iter().find(|v| &v.id == id).cloned();
+    let mut changes = Vec::new();
+    for var_a in a.iter() {
+        let var_b = match find(b, &var_a.id) {
+            Some(var) => var,
+            None => {
+                changes.push(VariableChange::Removed(var_a.clone()));
+                continue;
+            }
+        };
+        // Arrays that only changed length are compared by value
+        let (mut schema_a, mut schema_b) = (VariableSchema::of(var_a), VariableSchema::of(&var_b));
+        schema_a.len = None;
+        schema_b.len = None;
+        if schema_a != schema_b {
+            changes.push(VariableChange::Retyped {
+                id: var_a.id.clone(),
+                a: var_a.kind.clone(),
+                b: var_b.kind,
+            });
+        } else if var_a.kind != var_b.kind {
+            changes.push(VariableChange::Changed {
+                id: var_a.id.clone(),
+                delta: Delta::between(&var_a.kind, &var_b.kind),
+                a: var_a.kind.clone(),
+                b: var_b.kind,
+            });
+        }
+    }
+    for var_b in b.iter() {
+        if find(a, &var_b.id).is_none() {
+            changes.push(VariableChange::Added(var_b.clone()));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn var(id: &str, kind: VariableKind) -> Variable {
+        Variable {
+            id: OwnedMessageId::from_utf8(id),
+            kind,
+        }
+    }
+
+    #[test]
+    fn diff_with_deltas() {
+        let a = vec![
+            var("led", VariableKind::U16(200)),
+            var("wave", VariableKind::I8Array(vec![1, 2, 3])),
+            var("gain", VariableKind::F32(1.5.into())),
+            var("name", VariableKind::CharArray("a".to_owned())),
+            var("old", VariableKind::Callback),
+            var("same", VariableKind::U8(1)),
+        ];
+        let b = vec![
+            var("new", VariableKind::U8(7)),
+            var("same", VariableKind::U8(1)),
+            var("name", VariableKind::CharArray("b".to_owned())),
+            var("gain", VariableKind::F64(1.5.into())),
+            var("wave", VariableKind::I8Array(vec![1, -2, 5])),
+            var("led", VariableKind::U16(150)),
+        ];
+        let changes = diff(&a, &b);
+        assert_eq!(
+            changes,
+            vec![
+                VariableChange::Changed {
+                    id: a[0].id.clone(),
+                    a: a[0].kind.clone(),
+                    b: b[5].kind.clone(),
+                    delta: Some(Delta::Scalar(-50.0)),
+                },
+                VariableChange::Changed {
+                    id: a[1].id.clone(),
+                    a: a[1].kind.clone(),
+                    b: b[4].kind.clone(),
+                    delta: Some(Delta::Array(vec![0.0, -4.0, 2.0])),
+                },
+                VariableChange::Retyped {
+                    id: a[2].id.clone(),
+                    a: a[2].kind.clone(),
+                    b: b[3].kind.clone(),
+                },
+                VariableChange::Changed {
+                    id: a[3].id.clone(),
+                    a: a[3].kind.clone(),
+                    b: b[2].kind.clone(),
+                    delta: None,
+                },
+                VariableChange::Removed(a[4].clone()),
+                VariableChange::Added(b[0].clone()),
+            ]
+        );
+        assert_eq!(
+            changes[1].to_string(),
+            "* wave I8Array([1, 2, 3]) -> I8Array([1, -2, 5]) ([+0, -4, +2])"
+        );
+        assert_eq!(diff(&b, &b), vec![]);
+    }
+
+    #[test]
+    fn diff_resized_arrays() {
+        let a = vec![
+            var("wave", VariableKind::U16Array(vec![1, 2, 3])),
+            var("name", VariableKind::CharArray("ab".to_owned())),
+            var("gains", VariableKind::F32Array(vec![1.0.into()])),
+        ];
+        let b = vec![
+            var("wave", VariableKind::U16Array(vec![1, 4])),
+            var("name", VariableKind::CharArray("abc".to_owned())),
+            var("gains", VariableKind::U32Array(vec![1, 2])),
+        ];
+        let changes = diff(&a, &b);
+        assert_eq!(
+            changes,
+            vec![
+                VariableChange::Changed {
+                    id: a[0].id.clone(),
+                    a: a[0].kind.clone(),
+                    b: b[0].kind.clone(),
+                    delta: Some(Delta::Resized {
+                        prefix: vec![0.0, 2.0],
+                        len: -1,
+                    }),
+                },
+                VariableChange::Changed {
+                    id: a[1].id.clone(),
+                    a: a[1].kind.clone(),
+                    b: b[1].kind.clone(),
+                    delta: None,
+                },
+                VariableChange::Retyped {
+                    id: a[2].id.clone(),
+                    a: a[2].kind.clone(),
+                    b: b[2].kind.clone(),
+                },
+            ]
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "* wave U16Array([1, 2, 3]) -> U16Array([1, 4]) ([+0, +2], 1 removed)"
+        );
+        assert_eq!(
+            Delta::between(&b[0].kind, &a[0].kind).unwrap().to_string(),
+            "[+0, -2], 1 added"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::opts::DecodeOpts;
+use crate::output::{Format, Output};
+use bytes::BytesMut;
+use electricui_cli::capture::{Direction, Recorder};
+use electricui_cli::codec::FrameDecoder;
+use electricui_cli::prelude::*;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use thiserror::Error;
+use tracing::info;
+
+pub async fn decode(
+    opts: DecodeOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let bytes = match (&opts.input, &opts.hex) {
+        (Some(path), _) => fs::read(path)?,
+        (None, Some(hex)) => parse_hex(hex)?,
+        (None, None) => {
+            info!("Reading bytes from stdin");
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            bytes
+        }
+    };
+
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut dec = FrameDecoder::new(&mut dec_buf, opts.direction, false);
+    let mut recorder = match record {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+    let mut buf = BytesMut::from(&bytes[..]);
+    // Pasted dumps often omit the final delimiter
+    for (frame, entry) in dec.decode(&mut buf).into_iter().chain(dec.finish()) {
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.write(
+                opts.direction.unwrap_or(Direction::Rx),
+                &frame,
+                entry.error.as_deref(),
+            )?;
+        }
+        output.emit(&entry)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum HexError {
+    #[error("Hex string contains an invalid byte '{0}'")]
+    InvalidByte(String),
+
+    #[error("Hex string has an odd number of digits")]
+    OddLength,
+}
+
+/// Parses hex bytes, ignoring whitespace, commas and 0x prefixes
+fn parse_hex(s: &str) -> Result<Vec<u8>, HexError> {
+    let mut bytes = Vec::new();
+    for token in s.split(|c: char| c.is_whitespace() || c == ',') {
+        let token = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if token.len() % 2 != 0 {
+            return Err(HexError::OddLength);
+        }
+        for idx in (0..token.len()).step_by(2) {
+            let digits = token
+                .get(idx..idx + 2)
+                .ok_or_else(|| HexError::InvalidByte(token.to_owned()))?;
+            bytes.push(
+                u8::from_str_radix(digits, 16)
+                    .map_err(|_| HexError::InvalidByte(digits.to_owned()))?,
+            );
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_hex_bytes() {
+        assert_eq!(parse_hex("").unwrap(), Vec::<u8>::new());
+        assert_eq!(parse_hex("0102ff").unwrap(), vec![0x01, 0x02, 0xFF]);
+        assert_eq!(
+            parse_hex(" 0x01, 0X0203,ab\n\tCD ").unwrap(),
+            vec![0x01, 0x02, 0x03, 0xAB, 0xCD]
+        );
+        assert!(matches!(parse_hex("01 2"), Err(HexError::OddLength)));
+        assert!(matches!(parse_hex("0x123"), Err(HexError::OddLength)));
+        assert!(matches!(
+            parse_hex("01 zz"),
+            Err(HexError::InvalidByte(b)) if b == "zz"
+        ));
+        assert!(matches!(
+            parse_hex("0x0x"),
+            Err(HexError::InvalidByte(b)) if b == "0x"
+        ));
+    }
+}
@@ -1,23 +1,282 @@
-use crate::opts::DeviceOpts;
-use tokio_serial::{ClearBuffer, Error, SerialPort, SerialPortBuilderExt, SerialStream};
-use tracing::info;
-
-pub fn new(opts: &DeviceOpts) -> Result<SerialStream, Error> {
-    info!(
-        "Opening '{}', baud_rate={}, data_bits={:?}, parity={:?}, stop_bits={:?}",
-        opts.device, opts.baud_rate, opts.data_bits.0, opts.parity.0, opts.stop_bits.0
-    );
-
-    let mut port = tokio_serial::new(&opts.device, opts.baud_rate)
-        .data_bits(opts.data_bits.0)
-        .flow_control(opts.flow_control.0)
-        .parity(opts.parity.0)
-        .stop_bits(opts.stop_bits.0)
-        .open_native_async()?;
-    port.clear(ClearBuffer::All)?;
-
-    #[cfg(unix)]
-    port.set_exclusive(false)?;
-
-    Ok(port)
+use crate::opts::{DataBits, DeviceOpts, FlowControl, Parity, StopBits};
+use electricui_cli::{
+    capture::Recorder,
+    client::MAX_PACKET_SIZE,
+    heartbeat::HeartbeatMonitor,
+    link::LinkConfig,
+    schema::SchemaCache,
+    sim::{Schema, SimError, Simulator},
+    transport::BoxedTransport,
+};
+use std::path::Path;
+use std::{fmt, io, str::FromStr};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt};
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Serial(#[from] tokio_serial::Error),
+
+    #[error("Encountered an IO error while opening '{0}'")]
+    Io(String, #[source] io::Error),
+
+    #[error(transparent)]
+    Sim(#[from] SimError),
+
+    #[cfg(not(unix))]
+    #[error("Unix domain sockets are not supported on this platform")]
+    UnixUnsupported,
+}
+
+/// Where the device is reached.
+///
+/// Plain paths are serial devices, otherwise one of
+/// `serial:///dev/ttyUSB0?baud=115200`, `tcp://host:port`, `unix:///path/sock`
+/// or `sim://path/schema.toml` for an in-process simulated device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceUri {
+    Serial {
+        path: String,
+        baud_rate: Option<u32>,
+        data_bits: Option<DataBits>,
+        flow_control: Option<FlowControl>,
+        parity: Option<Parity>,
+        stop_bits: Option<StopBits>,
+    },
+    Tcp(String),
+    Unix(String),
+    Sim(String),
+}
+
+impl FromStr for DeviceUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+            None => ("serial".to_owned(), s),
+        };
+        if rest.is_empty() {
+            return Err("Device URI is missing a path or address".to_string());
+        }
+        match scheme.as_str() {
+            "serial" => {
+                let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let mut baud_rate = None;
+                let mut data_bits = None;
+                let mut flow_control = None;
+                let mut parity = None;
+                let mut stop_bits = None;
+                for param in query.split('&').filter(|p| !p.is_empty()) {
+                    let (key, value) = param
+                        .split_once('=')
+                        .ok_or_else(|| format!("Invalid serial URI parameter '{param}'"))?;
+                    match key {
+                        "baud" | "baud_rate" => {
+                            baud_rate =
+                                Some(value.parse().map_err(|_| "Invalid baud rate".to_string())?)
+                        }
+                        "data_bits" => data_bits = Some(value.parse()?),
+                        "flow_control" => flow_control = Some(value.parse()?),
+                        "parity" => parity = Some(value.parse()?),
+                        "stop_bits" => stop_bits = Some(value.parse()?),
+                        _ => return Err(format!("Unknown serial URI parameter '{key}'")),
+                    }
+                }
+                Ok(DeviceUri::Serial {
+                    path: path.to_owned(),
+                    baud_rate,
+                    data_bits,
+                    flow_control,
+                    parity,
+                    stop_bits,
+                })
+            }
+            "tcp" => Ok(DeviceUri::Tcp(rest.trim_end_matches('/').to_owned())),
+            "unix" => Ok(DeviceUri::Unix(rest.to_owned())),
+            "sim" => Ok(DeviceUri::Sim(rest.to_owned())),
+            _ => Err(format!("Unsupported device URI scheme '{scheme}'")),
+        }
+    }
+}
+
+impl fmt::Display for DeviceUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceUri::Serial { path, .. } => write!(f, "serial://{path}"),
+            DeviceUri::Tcp(addr) => write!(f, "tcp://{addr}"),
+            DeviceUri::Unix(path) => write!(f, "unix://{path}"),
+            DeviceUri::Sim(path) => write!(f, "sim://{path}"),
+        }
+    }
+}
+
+pub async fn new(opts: &DeviceOpts) -> Result<BoxedTransport, Error> {
+    match &opts.device {
+        DeviceUri::Serial {
+            path,
+            baud_rate,
+            data_bits,
+            flow_control,
+            parity,
+            stop_bits,
+        } => {
+            // Parameters in the URI take precedence over the options
+            let baud_rate = baud_rate.unwrap_or(opts.baud_rate);
+            let data_bits = data_bits.unwrap_or(opts.data_bits).0;
+            let flow_control = flow_control.unwrap_or(opts.flow_control).0;
+            let parity = parity.unwrap_or(opts.parity).0;
+            let stop_bits = stop_bits.unwrap_or(opts.stop_bits).0;
+            info!(
+                "Opening '{}', baud_rate={}, data_bits={:?}, parity={:?}, stop_bits={:?}",
+                path, baud_rate, data_bits, parity, stop_bits
+            );
+
+            let mut port = tokio_serial::new(path, baud_rate)
+                .data_bits(data_bits)
+                .flow_control(flow_control)
+                .parity(parity)
+                .stop_bits(stop_bits)
+                .open_native_async()?;
+            port.clear(ClearBuffer::All)?;
+
+            #[cfg(unix)]
+            port.set_exclusive(false)?;
+
+            Ok(Box::new(port))
+        }
+        DeviceUri::Tcp(addr) => {
+            info!("Connecting to '{}'", opts.device);
+            let stream = TcpStream::connect(addr)
+                .await
+                .map_err(|e| Error::Io(opts.device.to_string(), e))?;
+            stream
+                .set_nodelay(true)
+                .map_err(|e| Error::Io(opts.device.to_string(), e))?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(unix)]
+        DeviceUri::Unix(path) => {
+            info!("Connecting to '{}'", opts.device);
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .map_err(|e| Error::Io(opts.device.to_string(), e))?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(unix))]
+        DeviceUri::Unix(_) => Err(Error::UnixUnsupported),
+        DeviceUri::Sim(path) => {
+            info!("Starting simulated device from '{}'", path);
+            let mut sim = Simulator::new(Schema::load(path)?);
+            let (host, dev) = tokio::io::duplex(MAX_PACKET_SIZE * 4);
+            tokio::spawn(async move {
+                if let Err(e) = sim.serve(dev).await {
+                    warn!("Simulated device stopped. {e}");
+                }
+            });
+            Ok(Box::new(host))
+        }
+    }
+}
+
+/// Link settings from the device options, recording to `record` if given
+pub fn link_config(opts: &DeviceOpts, record: Option<&Path>) -> Result<LinkConfig, Error> {
+    let recorder = match record {
+        Some(path) => {
+            info!("Recording session to '{}'", path.display());
+            Some(Recorder::create(path).map_err(|e| Error::Io(path.display().to_string(), e))?)
+        }
+        None => None,
+    };
+    Ok(LinkConfig {
+        heartbeat: opts
+            .heartbeat
+            .map(|interval| HeartbeatMonitor::new(interval.into(), opts.heartbeat_misses)),
+        recorder,
+        timeout: opts.timeout.into(),
+        retries: opts.retries,
+    })
+}
+
+/// The schema cache in the user's cache directory, unless disabled
+pub fn schema_cache(opts: &DeviceOpts) -> Option<SchemaCache> {
+    if opts.no_schema_cache {
+        return None;
+    }
+    SchemaCache::default_dir().map(SchemaCache::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_device_uris() {
+        assert_eq!(
+            "/dev/ttyUSB0".parse(),
+            Ok(DeviceUri::Serial {
+                path: "/dev/ttyUSB0".to_owned(),
+                baud_rate: None,
+                data_bits: None,
+                flow_control: None,
+                parity: None,
+                stop_bits: None,
+            })
+        );
+        assert_eq!(
+            "SERIAL:///dev/ttyACM0?baud=9600&data_bits=7&flow_control=hw&parity=even&stop_bits=2"
+                .parse(),
+            Ok(DeviceUri::Serial {
+                path: "/dev/ttyACM0".to_owned(),
+                baud_rate: Some(9600),
+                data_bits: Some(DataBits(tokio_serial::DataBits::Seven)),
+                flow_control: Some(FlowControl(tokio_serial::FlowControl::Hardware)),
+                parity: Some(Parity(tokio_serial::Parity::Even)),
+                stop_bits: Some(StopBits(tokio_serial::StopBits::Two)),
+            })
+        );
+        assert_eq!(
+            "tcp://127.0.0.1:9000/".parse(),
+            Ok(DeviceUri::Tcp("127.0.0.1:9000".to_owned()))
+        );
+        assert_eq!(
+            "unix:///tmp/board.sock".parse(),
+            Ok(DeviceUri::Unix("/tmp/board.sock".to_owned()))
+        );
+        assert_eq!(
+            "sim://board.toml".parse(),
+            Ok(DeviceUri::Sim("board.toml".to_owned()))
+        );
+        assert_eq!(
+            "tcp://127.0.0.1:9000"
+                .parse::<DeviceUri>()
+                .unwrap()
+                .to_string(),
+            "tcp://127.0.0.1:9000"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_device_uris() {
+        for (uri, err) in [
+            ("tcp://", "Device URI is missing a path or address"),
+            ("", "Device URI is missing a path or address"),
+            ("http://board", "Unsupported device URI scheme 'http'"),
+            (
+                "serial:///dev/ttyUSB0?baud",
+                "Invalid serial URI parameter 'baud'",
+            ),
+            ("serial:///dev/ttyUSB0?baud=fast", "Invalid baud rate"),
+            ("serial:///dev/ttyUSB0?parity=maybe", "Invalid parity"),
+            (
+                "serial:///dev/ttyUSB0?speed=1",
+                "Unknown serial URI parameter 'speed'",
+            ),
+        ] {
+            assert_eq!(uri.parse::<DeviceUri>(), Err(err.to_owned()), "{uri}");
+        }
+    }
 }
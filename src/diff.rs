@@ -0,0 +1,131 @@
+use crate::device;
+use crate::opts::{DeviceOpts, DiffOpts, DiffTarget};
+use crate::output::{Format, Output, Render};
+use crate::snapshot::Snapshot;
+use electricui_cli::compare;
+use electricui_cli::prelude::*;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use thiserror::Error;
+
+pub async fn diff(
+    opts: DiffOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let a = variables(&opts.device, record).await?;
+    let b = match &opts.other {
+        DiffTarget::Device(uri) => {
+            let dev_opts = DeviceOpts {
+                device: uri.clone(),
+                ..opts.device.clone()
+            };
+            // Both sides recording to the same capture would interleave them
+            variables(&dev_opts, None).await?
+        }
+        DiffTarget::Snapshot(path) => {
+            let snapshot = Snapshot::load(path)?;
+            Side {
+                name: path.display().to_string(),
+                board_id: snapshot.board_id,
+                board_name: snapshot.board_name,
+                variables: snapshot.variables,
+            }
+        }
+    };
+
+    let report = DiffReport {
+        changes: compare::diff(&a.variables, &b.variables),
+        a,
+        b,
+    };
+    Output::new(format).emit(&report)?;
+    if !report.changes.is_empty() {
+        return Err(VariablesDifferError(report.changes.len()).into());
+    }
+    Ok(())
+}
+
+async fn variables(
+    opts: &DeviceOpts,
+    record: Option<&Path>,
+) -> Result<Side, Box<dyn std::error::Error + Send + Sync>> {
+    let dev = device::new(opts).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(dev, &mut dec_buf, device::link_config(opts, record)?);
+    Ok(Side {
+        name: opts.device.to_string(),
+        board_id: client.board_id().await?,
+        board_name: client.board_name().await?,
+        variables: client.tracked_variables().await?.as_slice().to_vec(),
+    })
+}
+
+/// One side of the comparison, a board or a snapshot
+#[derive(Debug, Serialize)]
+pub struct Side {
+    pub name: String,
+    pub board_id: BoardId,
+    pub board_name: BoardName,
+    #[serde(skip)]
+    pub variables: Vec<Variable>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub a: Side,
+    pub b: Side,
+    pub changes: Vec<VariableChange>,
+}
+
+impl Render for DiffReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (label, side) in [("a", &self.a), ("b", &self.b)] {
+            writeln!(
+                w,
+                "{label}: '{}' (0x{:04X}) from {}, {} variables",
+                side.board_name,
+                side.board_id,
+                side.name,
+                side.variables.len()
+            )?;
+        }
+        writeln!(w, "{} difference(s)", self.changes.len())?;
+        for change in self.changes.iter() {
+            writeln!(w, "  {change}")?;
+        }
+        Ok(())
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["id", "change", "a", "b", "delta"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.changes
+            .iter()
+            .map(|c| {
+                let (change, a, b, delta) = match c {
+                    VariableChange::Added(var) => ("added", None, Some(&var.kind), None),
+                    VariableChange::Removed(var) => ("removed", Some(&var.kind), None, None),
+                    VariableChange::Retyped { a, b, .. } => ("retyped", Some(a), Some(b), None),
+                    VariableChange::Changed { a, b, delta, .. } => {
+                        ("changed", Some(a), Some(b), delta.as_ref())
+                    }
+                };
+                vec![
+                    c.id().to_string(),
+                    change.to_owned(),
+                    a.map(|k| k.to_string()).unwrap_or_default(),
+                    b.map(|k| k.to_string()).unwrap_or_default(),
+                    delta.map(|d| d.to_string()).unwrap_or_default(),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("The variables differ in {0} place(s)")]
+pub struct VariablesDifferError(pub usize);
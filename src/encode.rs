@@ -0,0 +1,171 @@
+use crate::opts::EncodeOpts;
+use crate::output::{Format, Output, Render};
+use electricui_cli::capture::{Direction, Recorder};
+use electricui_cli::prelude::*;
+use electricui_embedded::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub async fn encode(
+    opts: EncodeOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (var, frames) = frames(&opts)?;
+    if let Some(path) = record {
+        let mut recorder = Recorder::create(path)?;
+        for frame in frames.iter() {
+            recorder.write(Direction::Tx, frame, None)?;
+        }
+    }
+    match &opts.output {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut stdout = io::stdout().lock();
+            for frame in frames.iter() {
+                stdout.write_all(frame)?;
+            }
+            stdout.flush()?;
+        }
+        Some(path) => fs::write(path, frames.concat())?,
+        None => Output::new(format).emit(&EncodeReport {
+            id: var.id.to_string(),
+            frames: frames.iter().map(|f| hex(f)).collect(),
+        })?,
+    }
+    Ok(())
+}
+
+/// Encodes the message described by `opts`, returning it with its framed packets
+#[allow(clippy::type_complexity)]
+fn frames(
+    opts: &EncodeOpts,
+) -> Result<(Variable, Vec<Vec<u8>>), Box<dyn std::error::Error + Send + Sync>> {
+    let kind = match (&opts.typ, &opts.value) {
+        (Some(typ), Some(value)) => VariableKind::parse(typ.0, None, value)?,
+        // Queries and callbacks carry no payload, a type always comes with a value
+        _ => VariableKind::Callback,
+    };
+    let var = Variable {
+        id: opts.id.clone(),
+        kind,
+    };
+
+    let pkts = if opts.internal {
+        let (_, data) = var.kind.to_wire()?;
+        let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(var.id.len(), data.len())];
+        var.encode_raw_request(
+            true,
+            opts.response,
+            opts.acknum.0,
+            &mut Packet::new_unchecked(&mut buf[..]),
+        )?;
+        vec![buf]
+    } else {
+        // Same path as a write, so large arrays become offset packets
+        var.encode_requests(opts.response, opts.acknum.0)?
+    };
+
+    let frames = pkts
+        .iter()
+        .map(|pkt| {
+            let mut frame = vec![0_u8; Framing::max_encoded_len(pkt.len())];
+            let len = Framing::encode_buf(pkt, &mut frame);
+            frame.truncate(len);
+            frame
+        })
+        .collect();
+    Ok((var, frames))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Serialize)]
+pub struct EncodeReport {
+    pub id: String,
+    /// Hex encoded frames, more than one when sent as offset packets
+    pub frames: Vec<String>,
+}
+
+impl Render for EncodeReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for frame in self.frames.iter() {
+            writeln!(w, "{frame}")?;
+        }
+        Ok(())
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["id", "frame", "hex"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(idx, f)| vec![self.id.clone(), idx.to_string(), f.clone()])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use electricui_cli::codec::FrameDecoder;
+    use pretty_assertions::assert_eq;
+    use structopt::StructOpt;
+
+    /// Encodes with the given arguments and decodes the frames again
+    fn round_trip(args: &[&str]) -> Vec<(Option<String>, Option<VariableKind>, bool, u8)> {
+        let opts = EncodeOpts::from_iter(["encode", "--id", "wave"].iter().chain(args));
+        let (_, frames) = frames(&opts).unwrap();
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut dec = FrameDecoder::new(&mut dec_buf, None, false);
+        let mut buf = BytesMut::from(&frames.concat()[..]);
+        dec.decode(&mut buf)
+            .into_iter()
+            .chain(dec.finish())
+            .map(|(_, e)| {
+                assert_eq!(e.error, None);
+                (e.id, e.value, e.response, e.acknum)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let id = Some("wave".to_owned());
+        assert_eq!(
+            round_trip(&[
+                "--type",
+                "i16",
+                "--value=-300",
+                "--response",
+                "--acknum",
+                "2"
+            ]),
+            vec![(id.clone(), Some(VariableKind::I16(-300)), true, 2)]
+        );
+        assert_eq!(
+            round_trip(&[]),
+            vec![(id.clone(), Some(VariableKind::Callback), false, 0)]
+        );
+
+        // Large arrays are sent as offset packets and reassembled
+        let values: Vec<String> = (0..1000).map(|v| v.to_string()).collect();
+        let decoded = round_trip(&["--type", "u16", "--value", &values.join(",")]);
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(
+            decoded.last().unwrap().1,
+            Some(VariableKind::U16Array((0..1000).collect()))
+        );
+    }
+}
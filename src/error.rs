@@ -1,4 +1,5 @@
 use derive_more::From;
+use electricui_embedded::message::MessageType;
 use std::{error, fmt, str};
 use thiserror::Error;
 
@@ -45,6 +46,9 @@ pub enum PacketProtocolError {
 
     #[error("Packet contains a protocol violation")]
     ProtocolViolation,
+
+    #[error("Message type {0} cannot be encoded")]
+    UnsupportedType(MessageType),
 }
 
 impl From<electricui_embedded::wire::packet::Error> for PacketProtocolError {
@@ -52,3 +56,27 @@ impl From<electricui_embedded::wire::packet::Error> for PacketProtocolError {
         PacketError(e).into()
     }
 }
+
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("Invalid message ID '{0}', must be 1 to 15 bytes")]
+pub struct InvalidMessageIdError(pub String);
+
+#[derive(Debug, Error)]
+#[error("Encountered end of stream unexpectedly")]
+pub struct EndOfStreamError;
+
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum ParseVariableError {
+    #[error("Invalid {0} value '{1}'")]
+    InvalidValue(MessageType, String),
+
+    #[error("Variables of type {0} cannot be parsed from text")]
+    UnsupportedType(MessageType),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+#[error("Board announced {announced} writable IDs but {received} were received")]
+pub struct IdsCountMismatchError {
+    pub announced: usize,
+    pub received: usize,
+}
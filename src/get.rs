@@ -0,0 +1,27 @@
+use crate::device;
+use crate::opts::GetOpts;
+use crate::output::{Format, Output};
+use electricui_cli::prelude::*;
+use std::path::Path;
+
+pub async fn get(
+    opts: GetOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    for id in opts.ids.iter() {
+        let var = client.get(id).await?;
+        output.emit(&var)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use crate::types::Heartbeat;
+use derive_more::Display;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Heartbeat values sent by a [`HeartbeatMonitor`], the rest are left to callers
+/// so their acks are never mistaken for the monitor's
+pub const MONITOR_HEARTBEATS: RangeInclusive<u8> = 0x80..=0xFF;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Display)]
+pub enum LinkEvent {
+    #[display(fmt = "link up")]
+    Up,
+    #[display(fmt = "link down")]
+    Down,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Serialize)]
+pub struct HeartbeatStats {
+    pub sent: usize,
+    pub acked: usize,
+    pub missed: usize,
+    pub mismatched: usize,
+    #[serde(serialize_with = "serialize_rtt")]
+    pub last_rtt: Option<Duration>,
+    #[serde(serialize_with = "serialize_rtt")]
+    pub min_rtt: Option<Duration>,
+    #[serde(serialize_with = "serialize_rtt")]
+    pub max_rtt: Option<Duration>,
+    #[serde(skip)]
+    pub total_rtt: Duration,
+}
+
+/// Round-trip times are serialized as fractional milliseconds
+fn serialize_rtt<S: Serializer>(rtt: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    rtt.map(|d| d.as_secs_f64() * 1000.0).serialize(serializer)
+}
+
+impl HeartbeatStats {
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        if self.acked == 0 {
+            None
+        } else {
+            Some(self.total_rtt / self.acked as u32)
+        }
+    }
+}
+
+impl fmt::Display for HeartbeatStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}, acked: {}, missed: {}, mismatched: {}",
+            self.sent, self.acked, self.missed, self.mismatched
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min_rtt, self.avg_rtt(), self.max_rtt) {
+            write!(f, ", rtt min/avg/max: {:?}/{:?}/{:?}", min, avg, max)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks heartbeat requests and acks to determine the health of the link.
+///
+/// The link is considered down after `max_missed` consecutive heartbeats
+/// go unanswered, and back up on the next matching ack.
+/// Requests cycle through [`MONITOR_HEARTBEATS`].
+#[derive(Debug)]
+pub struct HeartbeatMonitor {
+    interval: Duration,
+    max_missed: usize,
+    next_value: u8,
+    outstanding: Option<(Heartbeat, Instant)>,
+    consecutive_missed: usize,
+    link_up: Option<bool>,
+    stats: HeartbeatStats,
+}
+
+impl HeartbeatMonitor {
+    pub fn new(interval: Duration, max_missed: usize) -> Self {
+        Self {
+            interval,
+            max_missed: max_missed.max(1),
+            next_value: *MONITOR_HEARTBEATS.start(),
+            outstanding: None,
+            consecutive_missed: 0,
+            link_up: None,
+            stats: HeartbeatStats::default(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn stats(&self) -> &HeartbeatStats {
+        &self.stats
+    }
+
+    /// Returns true if an ack for a previous request is still pending
+    pub fn is_outstanding(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    /// Returns true if `hb` is in the range of values the monitor sends
+    pub fn is_monitor_heartbeat(hb: Heartbeat) -> bool {
+        MONITOR_HEARTBEATS.contains(&u8::from(hb))
+    }
+
+    /// Produces the next heartbeat to send, accounting for a still outstanding
+    /// previous request as missed.
+    pub fn next_request(&mut self, now: Instant) -> (Heartbeat, Option<LinkEvent>) {
+        let mut event = None;
+        if let Some((hb, _)) = self.outstanding.take() {
+            self.stats.missed += 1;
+            self.consecutive_missed += 1;
+            warn!(
+                "Missed heartbeat {hb} ({} consecutive)",
+                self.consecutive_missed
+            );
+            if self.consecutive_missed >= self.max_missed && self.link_up != Some(false) {
+                self.link_up = Some(false);
+                event = Some(LinkEvent::Down);
+            }
+        }
+
+        let hb = Heartbeat::from(self.next_value);
+        self.next_value = match self.next_value.checked_add(1) {
+            Some(v) if MONITOR_HEARTBEATS.contains(&v) => v,
+            _ => *MONITOR_HEARTBEATS.start(),
+        };
+        self.outstanding = Some((hb, now));
+        self.stats.sent += 1;
+        (hb, event)
+    }
+
+    pub fn on_response(&mut self, hb: Heartbeat, now: Instant) -> Option<LinkEvent> {
+        match self.outstanding {
+            Some((expected, sent_at)) if expected == hb => {
+                self.outstanding = None;
+                let rtt = now.duration_since(sent_at);
+                debug!("Heartbeat {hb} acked, rtt={rtt:?}");
+                self.stats.acked += 1;
+                self.stats.last_rtt = Some(rtt);
+                self.stats.min_rtt = Some(self.stats.min_rtt.map_or(rtt, |m| m.min(rtt)));
+                self.stats.max_rtt = Some(self.stats.max_rtt.map_or(rtt, |m| m.max(rtt)));
+                self.stats.total_rtt += rtt;
+                self.consecutive_missed = 0;
+                if self.link_up != Some(true) {
+                    self.link_up = Some(true);
+                    return Some(LinkEvent::Up);
+                }
+            }
+            _ => {
+                warn!("Received mismatched heartbeat {hb}");
+                self.stats.mismatched += 1;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const RTT: Duration = Duration::from_millis(5);
+
+    #[test]
+    fn acks_bring_the_link_up() {
+        let mut m = HeartbeatMonitor::new(Duration::from_secs(1), 2);
+        let t0 = Instant::now();
+        assert!(!m.is_outstanding());
+
+        let (hb, event) = m.next_request(t0);
+        assert_eq!(hb, Heartbeat::from(0x80));
+        assert_eq!(event, None);
+        assert!(m.is_outstanding());
+        assert_eq!(m.on_response(hb, t0 + RTT), Some(LinkEvent::Up));
+        assert!(!m.is_outstanding());
+
+        let (hb, _) = m.next_request(t0 + 10 * RTT);
+        assert_eq!(hb, Heartbeat::from(0x81));
+        // Already up
+        assert_eq!(m.on_response(hb, t0 + 13 * RTT), None);
+
+        let stats = m.stats();
+        assert_eq!((stats.sent, stats.acked, stats.missed), (2, 2, 0));
+        assert_eq!(stats.last_rtt, Some(3 * RTT));
+        assert_eq!(stats.min_rtt, Some(RTT));
+        assert_eq!(stats.max_rtt, Some(3 * RTT));
+        assert_eq!(stats.avg_rtt(), Some(2 * RTT));
+    }
+
+    #[test]
+    fn mismatched_acks_are_counted_and_ignored() {
+        let mut m = HeartbeatMonitor::new(Duration::from_secs(1), 1);
+        let t0 = Instant::now();
+        assert_eq!(m.on_response(Heartbeat::from(0x80), t0), None);
+
+        let (hb, _) = m.next_request(t0);
+        assert_eq!(m.on_response(Heartbeat::from(0x90), t0 + RTT), None);
+        assert!(m.is_outstanding());
+        assert_eq!(m.stats().mismatched, 2);
+        assert_eq!(m.on_response(hb, t0 + RTT), Some(LinkEvent::Up));
+        // A duplicate ack no longer matches anything
+        assert_eq!(m.on_response(hb, t0 + RTT), None);
+        assert_eq!(m.stats().mismatched, 3);
+        assert_eq!(m.stats().acked, 1);
+    }
+
+    #[test]
+    fn consecutive_misses_take_the_link_down() {
+        let mut m = HeartbeatMonitor::new(Duration::from_secs(1), 3);
+        let t0 = Instant::now();
+        let (hb, _) = m.next_request(t0);
+        assert_eq!(m.on_response(hb, t0), Some(LinkEvent::Up));
+
+        // Each request made while the previous one is outstanding is a miss
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            events.push(m.next_request(t0).1);
+        }
+        assert_eq!(
+            events,
+            vec![None, None, None, Some(LinkEvent::Down), None],
+            "down once, after the third consecutive miss"
+        );
+        assert_eq!(m.stats().missed, 4);
+
+        let (hb, event) = m.next_request(t0);
+        assert_eq!(event, None);
+        assert_eq!(m.on_response(hb, t0 + RTT), Some(LinkEvent::Up));
+
+        // An ack resets the consecutive count
+        m.next_request(t0);
+        m.next_request(t0);
+        let (_, event) = m.next_request(t0);
+        assert_eq!(event, None);
+        assert_eq!(m.stats().missed, 7);
+    }
+
+    #[test]
+    fn requests_stay_in_the_monitor_range() {
+        let mut m = HeartbeatMonitor::new(Duration::from_secs(1), 1);
+        let t0 = Instant::now();
+        let values: Vec<u8> = (0..=MONITOR_HEARTBEATS.len())
+            .map(|_| {
+                let (hb, _) = m.next_request(t0);
+                m.on_response(hb, t0);
+                u8::from(hb)
+            })
+            .collect();
+        assert_eq!(values[0], 0x80);
+        assert_eq!(values[127], 0xFF);
+        assert_eq!(values[128], 0x80);
+        assert!(values
+            .iter()
+            .all(|v| HeartbeatMonitor::is_monitor_heartbeat(Heartbeat::from(*v))));
+        assert!(!HeartbeatMonitor::is_monitor_heartbeat(Heartbeat::from(5)));
+    }
+}
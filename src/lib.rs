@@ -0,0 +1,17 @@
+//! An unofficial and incomplete library for devices implementing the ElectricUI Binary Protocol.
+//!
+//! See [`Client`](crate::client::Client) for the async API the `electricui` CLI is built on.
+
+pub mod capture;
+pub mod client;
+pub mod codec;
+pub mod compare;
+pub mod error;
+pub mod heartbeat;
+pub mod link;
+pub mod offset;
+pub mod prelude;
+pub mod schema;
+pub mod sim;
+pub mod transport;
+pub mod types;
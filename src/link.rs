@@ -0,0 +1,456 @@
+use crate::capture::Recorder;
+use crate::codec::{self, Codec};
+use crate::error::{EndOfStreamError, PacketProtocolError};
+use crate::heartbeat::{HeartbeatMonitor, HeartbeatStats, LinkEvent};
+use crate::types::Heartbeat;
+use bytes::Bytes;
+use electricui_embedded::prelude::*;
+use futures::stream::StreamExt;
+use futures::SinkExt;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error(transparent)]
+    Codec(#[from] codec::Error),
+
+    #[error(transparent)]
+    EndOfStream(#[from] EndOfStreamError),
+
+    #[error(transparent)]
+    Packet(#[from] PacketProtocolError),
+
+    #[error("Lost connection to the device, heartbeat {0}")]
+    LinkDown(HeartbeatStats),
+
+    #[error("Timed out waiting for the {step} response after {attempts} attempt(s)")]
+    Timeout { step: String, attempts: usize },
+}
+
+/// How a packet received during an exchange relates to the request
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Response {
+    /// Unrelated traffic
+    Ignore,
+    /// Part of the response, more packets are expected
+    More,
+    /// The final packet of the response
+    Done,
+}
+
+#[derive(Debug)]
+pub struct LinkConfig {
+    pub heartbeat: Option<HeartbeatMonitor>,
+    /// Records the session to a capture
+    pub recorder: Option<Recorder>,
+    /// How long to wait for each response packet
+    pub timeout: Duration,
+    /// How many times a request is re-sent after timing out
+    pub retries: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: None,
+            recorder: None,
+            timeout: Duration::from_secs(1),
+            retries: 2,
+        }
+    }
+}
+
+/// A framed connection to a device, with an optional heartbeat monitor
+/// that runs while waiting for packets.
+pub struct Link<'buf, T, const N: usize> {
+    codec: Framed<T, Codec<'buf, N>>,
+    heartbeat: Option<(HeartbeatMonitor, Interval)>,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl<'buf, T: AsyncRead + AsyncWrite + Unpin, const N: usize> Link<'buf, T, N> {
+    pub fn new(dev: T, dec_buf: &'buf mut [u8; N], config: LinkConfig) -> Self {
+        let heartbeat = config.heartbeat.map(|hb| {
+            let mut interval = time::interval(hb.interval());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            (hb, interval)
+        });
+        let codec = match config.recorder {
+            Some(recorder) => Codec::new(dec_buf).with_recorder(recorder),
+            None => Codec::new(dec_buf),
+        };
+        Self {
+            codec: Framed::new(dev, codec),
+            heartbeat,
+            timeout: config.timeout,
+            retries: config.retries,
+        }
+    }
+
+    pub fn heartbeat_stats(&self) -> Option<&HeartbeatStats> {
+        self.heartbeat.as_ref().map(|(hb, _)| hb.stats())
+    }
+
+    pub async fn send<P: AsRef<[u8]>>(&mut self, pkt: Packet<P>) -> Result<(), LinkError> {
+        self.codec.send(pkt).await?;
+        Ok(())
+    }
+
+    /// Sends `pkt` and waits for the single response packet matching the
+    /// internal flag and message ID, retrying on timeout.
+    pub async fn request<P: AsRef<[u8]>>(
+        &mut self,
+        step: &str,
+        pkt: &Packet<P>,
+        internal: bool,
+        response_id: &[u8],
+    ) -> Result<Packet<Bytes>, LinkError> {
+        let mut pkts = self
+            .exchange(step, pkt, |p, _| {
+                if is_response(p, internal, response_id) {
+                    Response::Done
+                } else {
+                    Response::Ignore
+                }
+            })
+            .await?;
+        Ok(pkts.remove(0))
+    }
+
+    /// Sends `pkt` and collects the response packets until `classify` reports
+    /// the response is done.
+    ///
+    /// `classify` is given each received packet and the number of response packets
+    /// collected so far. The whole exchange is retried when any response packet
+    /// fails to arrive within the timeout. Packets arriving within another timeout
+    /// after that are late replies to the failed attempt, and are discarded before retrying.
+    pub async fn exchange<P, F>(
+        &mut self,
+        step: &str,
+        pkt: &Packet<P>,
+        classify: F,
+    ) -> Result<Vec<Packet<Bytes>>, LinkError>
+    where
+        P: AsRef<[u8]>,
+        F: FnMut(&Packet<Bytes>, usize) -> Response,
+    {
+        self.exchange_all(step, std::slice::from_ref(pkt), classify)
+            .await
+    }
+
+    /// Like [`Link::exchange`], for requests spanning several packets such as
+    /// offset packets. Every packet is re-sent on retry.
+    pub async fn exchange_all<P, F>(
+        &mut self,
+        step: &str,
+        pkts: &[Packet<P>],
+        mut classify: F,
+    ) -> Result<Vec<Packet<Bytes>>, LinkError>
+    where
+        P: AsRef<[u8]>,
+        F: FnMut(&Packet<Bytes>, usize) -> Response,
+    {
+        let attempts = self.retries + 1;
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                self.discard_late_replies(step).await?;
+            }
+            debug!("Sending {step} request, attempt {attempt}/{attempts}");
+            for pkt in pkts.iter() {
+                self.send(Packet::new_unchecked(pkt.as_ref())).await?;
+            }
+
+            let mut response = Vec::new();
+            loop {
+                let pkt = match time::timeout(self.timeout, self.next()).await {
+                    Ok(pkt) => pkt?,
+                    Err(_) => {
+                        warn!("Timed out waiting for the {step} response, attempt {attempt}/{attempts}");
+                        break;
+                    }
+                };
+                match classify(&pkt, response.len()) {
+                    Response::Ignore => debug!("Ignoring unrelated packet {pkt}"),
+                    Response::More => response.push(pkt),
+                    Response::Done => {
+                        response.push(pkt);
+                        return Ok(response);
+                    }
+                }
+            }
+        }
+        Err(LinkError::Timeout {
+            step: step.to_owned(),
+            attempts,
+        })
+    }
+
+    /// Discards the packets received within a timeout, so replies to a timed out attempt
+    /// aren't mistaken for replies to its retry
+    async fn discard_late_replies(&mut self, step: &str) -> Result<(), LinkError> {
+        let deadline = Instant::now() + self.timeout;
+        while let Ok(pkt) = time::timeout_at(deadline, self.next()).await {
+            debug!("Discarding late {step} reply {}", pkt?);
+        }
+        Ok(())
+    }
+
+    /// Returns the next packet from the device.
+    ///
+    /// Heartbeats are sent and their acks consumed here when the monitor is enabled,
+    /// an error is returned once the link is considered down.
+    pub async fn next(&mut self) -> Result<Packet<Bytes>, LinkError> {
+        loop {
+            let next = {
+                let heartbeat = &mut self.heartbeat;
+                let codec = &mut self.codec;
+                let tick = async {
+                    match heartbeat.as_mut() {
+                        Some((_, interval)) => interval.tick().await,
+                        None => futures::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = tick => None,
+                    pkt = codec.next() => Some(pkt),
+                }
+            };
+
+            match next {
+                None => self.send_heartbeat().await?,
+                Some(pkt) => {
+                    let pkt = pkt.ok_or(EndOfStreamError)??;
+                    if let Some((monitor, _)) = self.heartbeat.as_mut() {
+                        // Acks outside the monitor's range are for heartbeats sent by the caller
+                        if is_heartbeat(&pkt) {
+                            let hb = Heartbeat::decode_response(&pkt)?;
+                            if HeartbeatMonitor::is_monitor_heartbeat(hb) {
+                                let event = monitor.on_response(hb, Instant::now());
+                                self.handle_event(event)?;
+                                continue;
+                            }
+                        }
+                    }
+                    return Ok(pkt);
+                }
+            }
+        }
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), LinkError> {
+        let (hb, event) = match self.heartbeat.as_mut() {
+            Some((monitor, _)) => monitor.next_request(Instant::now()),
+            None => return Ok(()),
+        };
+        self.handle_event(event)?;
+        debug!("Sending heartbeat {hb}");
+        let mut buf = [0_u8; Packet::<&[u8]>::BASE_PACKET_SIZE + 2];
+        let mut pkt = Packet::new_unchecked(&mut buf[..]);
+        hb.encode_request(&mut pkt)
+            .map_err(PacketProtocolError::from)?;
+        self.codec.send(pkt).await?;
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: Option<LinkEvent>) -> Result<(), LinkError> {
+        match (event, self.heartbeat.as_ref()) {
+            (Some(LinkEvent::Up), Some((monitor, _))) => {
+                info!("Heartbeat {}, {}", LinkEvent::Up, monitor.stats());
+                Ok(())
+            }
+            (Some(LinkEvent::Down), Some((monitor, _))) => {
+                warn!("Heartbeat {}, {}", LinkEvent::Down, monitor.stats());
+                Err(LinkError::LinkDown(*monitor.stats()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_heartbeat<T: AsRef<[u8]>>(pkt: &Packet<T>) -> bool {
+    is_response(pkt, true, MessageId::INTERNAL_HEARTBEAT.as_bytes())
+}
+
+/// Returns true if the packet has the given internal flag and message ID
+pub fn is_response<T: AsRef<[u8]>>(pkt: &Packet<T>, internal: bool, id: &[u8]) -> bool {
+    pkt.internal() == internal && pkt.msg_id_raw().map(|pkt_id| pkt_id == id).unwrap_or(false)
+}
+
+/// Returns true if the packet acknowledges a write to `id` sent with `acknum`
+pub fn is_ack<T: AsRef<[u8]>>(pkt: &Packet<T>, id: &[u8], acknum: u8) -> bool {
+    is_response(pkt, false, id) && pkt.acknum() == acknum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MAX_PACKET_SIZE;
+    use crate::types::{OwnedMessageId, Variable, VariableKind, VariableQuery};
+    use pretty_assertions::assert_eq;
+    use tokio::io::DuplexStream;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn led() -> OwnedMessageId {
+        OwnedMessageId::new(b"led").unwrap()
+    }
+
+    fn reply(value: u16) -> Vec<u8> {
+        let var = Variable {
+            id: led(),
+            kind: VariableKind::U16(value),
+        };
+        var.encode_requests(false, 0).unwrap().remove(0)
+    }
+
+    /// A device answering the nth request with the replies `respond(n)` returns,
+    /// each delayed by its duration
+    fn spawn_device<F>(dev: DuplexStream, respond: F)
+    where
+        F: Fn(u16) -> Vec<(Duration, u16)> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+            let mut codec = Framed::new(dev, Codec::new(&mut dec_buf));
+            let mut n = 0;
+            while let Some(Ok(_)) = codec.next().await {
+                n += 1;
+                for (delay, value) in respond(n) {
+                    time::sleep(delay).await;
+                    codec
+                        .send(Packet::new_unchecked(reply(value)))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+    }
+
+    async fn query<T: AsyncRead + AsyncWrite + Unpin, const N: usize>(
+        link: &mut Link<'_, T, N>,
+        packets: usize,
+    ) -> Result<Vec<VariableKind>, LinkError> {
+        let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(3, 0)];
+        let mut pkt = Packet::new_unchecked(&mut buf[..]);
+        VariableQuery::encode_request(&led(), &mut pkt).unwrap();
+        let pkts = link
+            .exchange("led", &pkt, |p, received| {
+                match (is_response(p, false, b"led"), received + 1 == packets) {
+                    (false, _) => Response::Ignore,
+                    (true, false) => Response::More,
+                    (true, true) => Response::Done,
+                }
+            })
+            .await?;
+        Ok(pkts
+            .iter()
+            .map(|p| Variable::decode_response(p).unwrap().kind)
+            .collect())
+    }
+
+    fn link(
+        host: DuplexStream,
+        dec_buf: &mut [u8; MAX_PACKET_SIZE],
+    ) -> Link<'_, DuplexStream, MAX_PACKET_SIZE> {
+        let config = LinkConfig {
+            timeout: TIMEOUT,
+            retries: 2,
+            ..Default::default()
+        };
+        Link::new(host, dec_buf, config)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_reply_is_not_taken_for_the_retry() {
+        let (host, dev) = tokio::io::duplex(1024);
+        spawn_device(dev, |n| match n {
+            1 => vec![(TIMEOUT * 3 / 2, 1)],
+            n => vec![(Duration::ZERO, n)],
+        });
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut link = link(host, &mut dec_buf);
+
+        assert_eq!(
+            query(&mut link, 1).await.unwrap(),
+            vec![VariableKind::U16(2)]
+        );
+        // Nothing stale is left for the next exchange either
+        assert_eq!(
+            query(&mut link, 1).await.unwrap(),
+            vec![VariableKind::U16(3)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_response_is_not_completed_by_the_retry() {
+        let (host, dev) = tokio::io::duplex(1024);
+        spawn_device(dev, |n| match n {
+            1 => vec![(Duration::ZERO, 10), (TIMEOUT * 3 / 2, 11)],
+            n => vec![(Duration::ZERO, n * 10), (Duration::ZERO, n * 10 + 1)],
+        });
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut link = link(host, &mut dec_buf);
+
+        assert_eq!(
+            query(&mut link, 2).await.unwrap(),
+            vec![VariableKind::U16(20), VariableKind::U16(21)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_after_every_attempt() {
+        let (host, dev) = tokio::io::duplex(1024);
+        spawn_device(dev, |_| Vec::new());
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let mut link = link(host, &mut dec_buf);
+
+        match query(&mut link, 1).await {
+            Err(LinkError::Timeout { step, attempts }) => {
+                assert_eq!((step.as_str(), attempts), ("led", 3))
+            }
+            res => panic!("Expected a timeout, got {res:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_heartbeat_is_a_protocol_error() {
+        let (host, dev) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let id = MessageId::INTERNAL_HEARTBEAT.as_bytes();
+            let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(id.len(), 0)];
+            let mut pkt = Packet::new_unchecked(&mut buf[..]);
+            pkt.set_data_length(0).unwrap();
+            pkt.set_typ(MessageType::U8);
+            pkt.set_internal(true);
+            pkt.set_offset(false);
+            pkt.set_id_length(id.len() as _).unwrap();
+            pkt.set_response(false);
+            pkt.set_acknum(0);
+            pkt.msg_id_mut().unwrap().copy_from_slice(id);
+            pkt.set_checksum(pkt.compute_checksum().unwrap()).unwrap();
+
+            let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+            let mut codec = Framed::new(dev, Codec::new(&mut dec_buf));
+            codec.send(Packet::new_unchecked(buf)).await.unwrap();
+            // Keep the stream open while the link reads
+            while let Some(Ok(_)) = codec.next().await {}
+        });
+        let mut dec_buf = [0_u8; MAX_PACKET_SIZE];
+        let config = LinkConfig {
+            heartbeat: Some(HeartbeatMonitor::new(Duration::from_secs(60), 3)),
+            ..Default::default()
+        };
+        let mut link = Link::new(host, &mut dec_buf, config);
+
+        assert!(matches!(
+            link.next().await,
+            Err(LinkError::Packet(PacketProtocolError::ProtocolViolation))
+        ));
+    }
+}
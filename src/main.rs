@@ -1,19 +1,29 @@
 //! An unofficial and incomplete CLI for devices implementing the ElectricUI Binary Protocol.
 
-// TODO
-// - add subcmds for get/set, monitor/watch (spin up a heartbeat monitor task, show vars)
 //#![deny(warnings, clippy::all)]
 
 use crate::opts::{Opts, Subcommand};
 use structopt::StructOpt;
 use tracing::{debug, error};
 
+mod call;
 mod check;
-mod codec;
+mod decode;
 mod device;
-mod error;
+mod diff;
+mod encode;
+mod get;
 mod opts;
-mod types;
+mod output;
+mod replay;
+mod set;
+mod shell;
+mod simulate;
+mod snapshot;
+mod sniff;
+mod tui;
+mod verify;
+mod watch;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -29,7 +39,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
     let opts = Opts::from_args();
-    try_init_tracing_subscriber()?;
+    // The dashboard owns the terminal, log lines would corrupt it
+    if !matches!(opts.subcommand, Subcommand::Tui(_)) {
+        try_init_tracing_subscriber()?;
+    }
 
     let intr = interruptor::Interruptor::new();
     ctrlc::set_handler(move || {
@@ -49,10 +62,26 @@ async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
     })?;
 
     let mut cmd_handle = tokio::spawn(async move {
+        let format = opts.format;
+        let record = opts.record.as_deref();
         match opts.subcommand {
-            Subcommand::Check(c) => check::check(c),
+            Subcommand::Check(c) => check::check(c, format, record).await,
+            Subcommand::Get(c) => get::get(c, format, record).await,
+            Subcommand::Set(c) => set::set(c, format, record).await,
+            Subcommand::Call(c) => call::call(c, format, record).await,
+            Subcommand::Watch(c) => watch::watch(c, format, record).await,
+            Subcommand::Shell(c) => shell::shell(c, format, record).await,
+            Subcommand::Tui(c) => tui::tui(c, record).await,
+            Subcommand::Sniff(c) => sniff::sniff(c, format, record).await,
+            Subcommand::Decode(c) => decode::decode(c, format, record).await,
+            Subcommand::Encode(c) => encode::encode(c, format, record).await,
+            Subcommand::Schema(c) => verify::schema(c, record).await,
+            Subcommand::Verify(c) => verify::verify(c, format, record).await,
+            Subcommand::Snapshot(c) => snapshot::snapshot(c, format, record).await,
+            Subcommand::Diff(c) => diff::diff(c, format, record).await,
+            Subcommand::Replay(c) => replay::replay(c, format, record).await,
+            Subcommand::Simulate(c) => simulate::simulate(c).await,
         }
-        .await
     });
 
     tokio::select! {
@@ -0,0 +1,383 @@
+//! Offset packets, used to transfer variables whose payload doesn't fit in a single packet.
+//!
+//! The sender first sends an [`OffsetMetadata`] packet with the variable's message ID,
+//! carrying the base and end byte addresses of the range being transferred.
+//! The range then follows in chunks, from the end towards the base, each with the
+//! offset flag set, the variable's type and a little-endian u16 address between the
+//! message ID and the payload. The checksum covers the address bytes.
+
+use crate::error::{PacketError, PacketProtocolError};
+use crate::types::{OwnedMessageId, Variable, VariableKind};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::Crc;
+use electricui_embedded::{prelude::*, wire::packet};
+use std::collections::HashMap;
+use std::ops::Range;
+use tracing::debug;
+
+pub const OFFSET_SIZE: usize = Packet::<&[u8]>::OFFSET_SIZE;
+
+/// The 10 bit data length field can't hold a full 1024 byte payload,
+/// so chunks are the largest size that's a multiple of every element size
+pub const MAX_CHUNK_SIZE: usize = (Packet::<&[u8]>::MAX_PAYLOAD_SIZE - 1) & !7;
+
+/// Largest packet on the wire, a chunk with the longest message ID and a full payload
+pub const MAX_OFFSET_PACKET_SIZE: usize = Packet::<&[u8]>::MAX_PACKET_SIZE + OFFSET_SIZE;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct OffsetMetadata {
+    pub base: u16,
+    pub end: u16,
+}
+
+impl OffsetMetadata {
+    pub const WIRE_SIZE: usize = 4;
+
+    pub fn len(&self) -> usize {
+        usize::from(self.end.saturating_sub(self.base))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn decode_response<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<Self, PacketProtocolError> {
+        if p.typ() != MessageType::OffsetMetadata || p.offset() {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        let data = p.payload()?;
+        if data.len() != Self::WIRE_SIZE {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        let meta = OffsetMetadata {
+            base: LittleEndian::read_u16(&data[0..2]),
+            end: LittleEndian::read_u16(&data[2..4]),
+        };
+        if meta.end < meta.base {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        Ok(meta)
+    }
+
+    fn encode(&self, id: &OwnedMessageId) -> Result<Vec<u8>, PacketError> {
+        let mut payload = [0_u8; Self::WIRE_SIZE];
+        LittleEndian::write_u16(&mut payload[0..2], self.base);
+        LittleEndian::write_u16(&mut payload[2..4], self.end);
+        let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(id.len(), payload.len())];
+        let mut p = Packet::new_unchecked(&mut buf[..]);
+        p.set_data_length(payload.len() as _)?;
+        p.set_typ(MessageType::OffsetMetadata);
+        p.set_internal(false);
+        p.set_offset(false);
+        p.set_id_length(id.len() as _)?;
+        p.set_response(false);
+        p.set_acknum(0);
+        p.msg_id_mut()?.copy_from_slice(id.as_bytes());
+        p.payload_mut()?.copy_from_slice(&payload);
+        p.set_checksum(p.compute_checksum()?)?;
+        Ok(buf)
+    }
+}
+
+/// Returns the size of a packet on the wire, including the address of offset packets
+pub fn wire_size<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<usize, PacketError> {
+    let size = p.wire_size()?;
+    Ok(if p.offset() { size + OFFSET_SIZE } else { size })
+}
+
+/// Returns the address of an offset packet's payload
+pub fn address<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<u16, PacketProtocolError> {
+    let start = address_start(p)?;
+    Ok(LittleEndian::read_u16(
+        &p.as_ref()[start..start + OFFSET_SIZE],
+    ))
+}
+
+/// Returns an offset packet's payload, following its address
+pub fn payload<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<&[u8], PacketProtocolError> {
+    let start = address_start(p)? + OFFSET_SIZE;
+    Ok(&p.as_ref()[start..start + usize::from(p.data_length())])
+}
+
+fn address_start<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<usize, PacketProtocolError> {
+    if !p.offset() {
+        return Err(PacketProtocolError::ProtocolViolation);
+    }
+    if p.as_ref().len() < wire_size(p)? {
+        return Err(PacketError(packet::Error::IncompletePayload).into());
+    }
+    Ok(Packet::<&[u8]>::HEADER_SIZE + p.id_length()?)
+}
+
+/// Checks a complete offset packet, whose checksum follows the payload and covers the address
+pub fn check_packet(buf: &[u8]) -> Result<(), packet::Error> {
+    let p = Packet::new_unchecked(buf);
+    p.check_len()?;
+    let size = wire_size(&p).map_err(|e| e.0)?;
+    if buf.len() < size {
+        return Err(packet::Error::IncompletePayload);
+    }
+    let crc_start = size - Packet::<&[u8]>::CHECKSUM_SIZE;
+    let provided = LittleEndian::read_u16(&buf[crc_start..size]);
+    if checksum(&buf[..crc_start]) != provided {
+        return Err(packet::Error::InvalidChecksum);
+    }
+    Ok(())
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    Crc::<u16>::new(&Packet::<&[u8]>::CRC16_CCITT_FALSE).checksum(bytes)
+}
+
+/// Encodes a variable as an offset metadata packet followed by its chunks, the final
+/// chunk carrying the response flag and acknum
+pub fn encode_chunks(
+    var: &Variable,
+    response: bool,
+    acknum: u8,
+) -> Result<Vec<Vec<u8>>, PacketProtocolError> {
+    let (typ, data) = var.kind.to_wire_unbounded()?;
+    let meta = OffsetMetadata {
+        base: 0,
+        end: u16::try_from(data.len())
+            .map_err(|_| PacketError(packet::Error::InvalidDataLength))?,
+    };
+    let mut pkts = vec![meta.encode(&var.id)?];
+    let mut end = data.len();
+    while end > 0 {
+        let start = end.saturating_sub(MAX_CHUNK_SIZE);
+        let last = start == 0;
+        pkts.push(encode_chunk(
+            &var.id,
+            typ,
+            start as u16,
+            &data[start..end],
+            last && response,
+            if last { acknum } else { 0 },
+        )?);
+        end = start;
+    }
+    Ok(pkts)
+}
+
+fn encode_chunk(
+    id: &OwnedMessageId,
+    typ: MessageType,
+    address: u16,
+    payload: &[u8],
+    response: bool,
+    acknum: u8,
+) -> Result<Vec<u8>, PacketError> {
+    let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(id.len(), payload.len()) + OFFSET_SIZE];
+    let mut p = Packet::new_unchecked(&mut buf[..]);
+    p.set_data_length(payload.len() as _)?;
+    p.set_typ(typ);
+    p.set_internal(false);
+    p.set_offset(true);
+    p.set_id_length(id.len() as _)?;
+    p.set_response(response);
+    p.set_acknum(acknum);
+    p.msg_id_mut()?.copy_from_slice(id.as_bytes());
+
+    let start = Packet::<&[u8]>::HEADER_SIZE + id.len();
+    LittleEndian::write_u16(&mut buf[start..start + OFFSET_SIZE], address);
+    let crc_start = start + OFFSET_SIZE + payload.len();
+    buf[start + OFFSET_SIZE..crc_start].copy_from_slice(payload);
+    let crc = checksum(&buf[..crc_start]);
+    LittleEndian::write_u16(&mut buf[crc_start..], crc);
+    Ok(buf)
+}
+
+/// Reassembles variables sent as offset packets, passing regular variables through
+#[derive(Clone, Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<OwnedMessageId, Pending>,
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    meta: OffsetMetadata,
+    typ: Option<MessageType>,
+    data: Vec<u8>,
+    /// Sorted, non-overlapping byte ranges that have arrived
+    received: Vec<Range<usize>>,
+}
+
+impl Pending {
+    fn insert(&mut self, chunk: Range<usize>) {
+        let (mut start, mut end) = (chunk.start, chunk.end);
+        self.received.retain(|r| {
+            if r.end < start || r.start > end {
+                return true;
+            }
+            start = start.min(r.start);
+            end = end.max(r.end);
+            false
+        });
+        let at = self.received.partition_point(|r| r.start < start);
+        self.received.insert(at, start..end);
+    }
+
+    fn received_len(&self) -> usize {
+        self.received.iter().map(|r| r.len()).sum()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received_len() == self.meta.len()
+    }
+}
+
+impl Reassembler {
+    /// Returns the variable once all of its chunks have been received
+    pub fn push<T: AsRef<[u8]>>(
+        &mut self,
+        p: &Packet<T>,
+    ) -> Result<Option<Variable>, PacketProtocolError> {
+        if p.offset() {
+            let id = OwnedMessageId::from_wire(&p.msg_id()?);
+            let pending = self
+                .pending
+                .get_mut(&id)
+                .ok_or(PacketProtocolError::ProtocolViolation)?;
+            let chunk = payload(p)?;
+            let start = address(p)?
+                .checked_sub(pending.meta.base)
+                .map(usize::from)
+                .ok_or(PacketProtocolError::ProtocolViolation)?;
+            let end = start + chunk.len();
+            if end > pending.meta.len() || pending.typ.is_some_and(|t| t != p.typ()) {
+                return Err(PacketProtocolError::ProtocolViolation);
+            }
+            pending.typ = Some(p.typ());
+            pending.data[start..end].copy_from_slice(chunk);
+            pending.insert(start..end);
+            debug!(
+                "Received '{id}' chunk {start}..{end}, {}/{} bytes",
+                pending.received_len(),
+                pending.meta.len()
+            );
+            if !pending.is_complete() {
+                return Ok(None);
+            }
+            let Some(Pending {
+                typ: Some(typ),
+                data,
+                ..
+            }) = self.pending.remove(&id)
+            else {
+                return Err(PacketProtocolError::ProtocolViolation);
+            };
+            let kind = VariableKind::from_wire(typ, &data)?;
+            Ok(Some(Variable { id, kind }))
+        } else if p.typ() == MessageType::OffsetMetadata {
+            let id = OwnedMessageId::from_wire(&p.msg_id()?);
+            let meta = OffsetMetadata::decode_response(p)?;
+            if meta.is_empty() {
+                return Err(PacketProtocolError::ProtocolViolation);
+            }
+            debug!("Receiving '{id}' in chunks, {} bytes", meta.len());
+            self.pending.insert(
+                id,
+                Pending {
+                    meta,
+                    typ: None,
+                    data: vec![0; meta.len()],
+                    received: Vec::new(),
+                },
+            );
+            Ok(None)
+        } else {
+            Variable::decode_response(p).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec;
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn waveform() -> Variable {
+        Variable {
+            id: OwnedMessageId::new(b"wave").unwrap(),
+            kind: VariableKind::U16Array((0..1500).collect()),
+        }
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let var = waveform();
+        let pkts = encode_chunks(&var, true, 3).unwrap();
+        // Metadata then 3000 bytes in 3 chunks, from the end towards the base
+        assert_eq!(pkts.len(), 4);
+        let addresses: Vec<u16> = pkts[1..]
+            .iter()
+            .map(|b| address(&Packet::new_unchecked(&b[..])).unwrap())
+            .collect();
+        assert_eq!(addresses, vec![1984, 968, 0]);
+        let last = Packet::new_unchecked(&pkts[3][..]);
+        assert!(last.response());
+        assert_eq!(last.acknum(), 3);
+
+        let mut enc = codec::Encoder::default();
+        let mut wire = BytesMut::new();
+        for pkt in pkts.iter() {
+            let mut frame = BytesMut::new();
+            enc.encode(Packet::new_unchecked(&pkt[..]), &mut frame)
+                .unwrap();
+            wire.extend_from_slice(&frame);
+        }
+
+        let mut dec_buf = [0_u8; MAX_OFFSET_PACKET_SIZE];
+        let mut dec = codec::Decoder::new(&mut dec_buf);
+        let mut reassembler = Reassembler::default();
+        let mut vars = Vec::new();
+        while let Some(pkt) = dec.decode(&mut wire).unwrap() {
+            if let Some(v) = reassembler.push(&pkt).unwrap() {
+                vars.push(v);
+            }
+        }
+        assert_eq!(vars, vec![var]);
+    }
+
+    #[test]
+    fn rejects_chunks_outside_the_range() {
+        let mut reassembler = Reassembler::default();
+        let pkts = encode_chunks(&waveform(), false, 0).unwrap();
+        assert_eq!(
+            reassembler.push(&Packet::new_unchecked(&pkts[1][..])),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+
+        let short = Variable {
+            id: waveform().id,
+            kind: VariableKind::U16Array(vec![1, 2]),
+        };
+        let short_pkts = encode_chunks(&short, false, 0).unwrap();
+        assert_eq!(
+            reassembler.push(&Packet::new_unchecked(&short_pkts[0][..])),
+            Ok(None)
+        );
+        assert_eq!(
+            reassembler.push(&Packet::new_unchecked(&pkts[3][..])),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn duplicate_chunks_do_not_complete_the_variable() {
+        let var = waveform();
+        let pkts = encode_chunks(&var, false, 0).unwrap();
+        let mut reassembler = Reassembler::default();
+        for pkt in [&pkts[0], &pkts[1], &pkts[2], &pkts[2]] {
+            assert_eq!(reassembler.push(&Packet::new_unchecked(&pkt[..])), Ok(None));
+        }
+        assert_eq!(
+            reassembler.push(&Packet::new_unchecked(&pkts[3][..])),
+            Ok(Some(var))
+        );
+    }
+}
@@ -1,3 +1,9 @@
+use crate::device::DeviceUri;
+use crate::output::Format;
+use electricui_cli::capture::Direction;
+use electricui_cli::types::{OwnedMessageId, MAX_ACKNUM};
+use electricui_embedded::message::MessageType;
+use std::path::PathBuf;
 use std::str::FromStr;
 use structopt::StructOpt;
 
@@ -6,6 +12,15 @@ use structopt::StructOpt;
 #[structopt(name = "electricui")]
 #[structopt(help_message = "Prints help information. Use --help for more details.")]
 pub struct Opts {
+    /// Output format: text, json, yaml or csv
+    #[structopt(long, global = true, default_value = "text")]
+    pub format: Format,
+
+    /// Record every packet and decoder error crossing the wire to a capture file,
+    /// e.g. session.euicap
+    #[structopt(long, global = true)]
+    pub record: Option<PathBuf>,
+
     #[structopt(flatten)]
     pub subcommand: Subcommand,
 }
@@ -14,11 +29,304 @@ pub struct Opts {
 pub enum Subcommand {
     /// TODO
     Check(DeviceOpts),
+
+    /// Read one or more variables by message ID
+    Get(GetOpts),
+
+    /// Write one or more variables by message ID
+    Set(SetOpts),
+
+    /// Invoke one or more callbacks by message ID
+    Call(CallOpts),
+
+    /// Stream timestamped variable values as they are polled or pushed by the board
+    Watch(WatchOpts),
+
+    /// Interactive prompt over a single connection, handshaking once
+    Shell(ShellOpts),
+
+    /// Full-screen dashboard of the board's variables and link health
+    Tui(TuiOpts),
+
+    /// Passively decode and print traffic without transmitting anything
+    Sniff(SniffOpts),
+
+    /// Decode packets from a hex string, a raw byte file or stdin, without a device
+    Decode(DecodeOpts),
+
+    /// Encode a message to framed wire bytes, without a device
+    Encode(EncodeOpts),
+
+    /// Board schemas, the message IDs and types of the board's variables
+    Schema(SchemaCommand),
+
+    /// Compare the board's variables against a schema file and report every difference
+    Verify(VerifyOpts),
+
+    /// Save the board's writable variables to a JSON file, or write them back
+    Snapshot(SnapshotCommand),
+
+    /// Compare the board's variables against another board or a snapshot file
+    Diff(DiffOpts),
+
+    /// Re-send the host to device packets of a capture and compare the device's replies
+    Replay(ReplayOpts),
+
+    /// Run a simulated device described by a schema file, on a PTY or socket
+    Simulate(SimulateOpts),
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SimulateOpts {
+    /// Listen for connections on a tcp://host:port or unix:///path URI instead of a PTY
+    #[structopt(short = "l", long)]
+    pub listen: Option<DeviceUri>,
+
+    /// Schema file describing the board and its variables
+    #[structopt(name = "schema")]
+    pub schema: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct ShellOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// History file, defaults to electricui/history in the user's data directory
+    #[structopt(long)]
+    pub history: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct TuiOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Tracked variables polling interval, e.g. 100ms or 2s
+    #[structopt(short = "i", long, default_value = "250ms")]
+    pub interval: humantime::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SniffOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Direction of the tapped traffic, tx (host to device) or rx (device to host), if known
+    #[structopt(long)]
+    pub direction: Option<Direction>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct DecodeOpts {
+    /// File of raw framed bytes, e.g. a logic analyzer export
+    #[structopt(short = "i", long, conflicts_with = "hex")]
+    pub input: Option<PathBuf>,
+
+    /// Hex encoded framed bytes, e.g. "08 02 20 03" or "0x08,0x02". Whitespace, commas and 0x prefixes are ignored
+    #[structopt(long)]
+    pub hex: Option<String>,
+
+    /// Direction of the bytes, tx (host to device) or rx (device to host), if known
+    #[structopt(long)]
+    pub direction: Option<Direction>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct EncodeOpts {
+    /// Message ID
+    #[structopt(long)]
+    pub id: OwnedMessageId,
+
+    /// Variable type of the value
+    #[structopt(short = "t", long = "type", requires = "value")]
+    pub typ: Option<VariableType>,
+
+    /// Value to encode, arrays are comma separated. Omit along with the type for an empty
+    /// (query or callback) message
+    #[structopt(long, requires = "typ")]
+    pub value: Option<String>,
+
+    /// Set the internal flag
+    #[structopt(long)]
+    pub internal: bool,
+
+    /// Set the response flag, requesting a reply
+    #[structopt(long)]
+    pub response: bool,
+
+    /// Acknum, 1 to 7, the board echoes back. Zero means no acknowledgement
+    #[structopt(long, default_value = "0")]
+    pub acknum: Acknum,
+
+    /// Write the raw framed bytes to a file instead of printing hex, '-' for stdout
+    #[structopt(short = "o", long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub enum SchemaCommand {
+    /// Write the board's schema as TOML, for use with verify
+    Export(SchemaExportOpts),
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SchemaExportOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Write the schema to a file instead of stdout
+    #[structopt(short = "o", long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct VerifyOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Expected schema, written by schema export
+    #[structopt(name = "schema")]
+    pub schema: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub enum SnapshotCommand {
+    /// Record the current value of every writable variable
+    Save(SnapshotSaveOpts),
+
+    /// Write the values of a snapshot back to the board, skipping callbacks
+    Restore(SnapshotRestoreOpts),
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SnapshotSaveOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Snapshot file to write, e.g. board.json
+    #[structopt(name = "snapshot")]
+    pub snapshot: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SnapshotRestoreOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Wait for the board to acknowledge each write, retransmitting on timeout
+    #[structopt(short = "a", long)]
+    pub ack: bool,
+
+    /// Snapshot file to restore, written by snapshot save
+    #[structopt(name = "snapshot")]
+    pub snapshot: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct DiffOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Second device path or URI, sharing the first device's options, or a snapshot file
+    /// ending in .json
+    #[structopt(name = "other")]
+    pub other: DiffTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct ReplayOpts {
+    /// Capture file to replay, recorded with --record
+    #[structopt(name = "capture")]
+    pub capture: PathBuf,
+
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Playback speed relative to the recorded timing, e.g. 2x or 0.5x
+    #[structopt(long, default_value = "1x")]
+    pub speed: Speed,
+
+    /// Send each request as soon as the previous one has been answered
+    #[structopt(long, conflicts_with = "speed")]
+    pub as_fast_as_possible: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct GetOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Message IDs of the variables to read
+    #[structopt(name = "id", required = true)]
+    pub ids: Vec<OwnedMessageId>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct SetOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Variable type to write, instead of querying the board for each variable's type
+    #[structopt(short = "t", long = "type")]
+    pub typ: Option<VariableType>,
+
+    /// Read each variable back after writing it to confirm the new value
+    #[structopt(long)]
+    pub verify: bool,
+
+    /// Wait for the board to acknowledge each write, retransmitting on timeout
+    #[structopt(short = "a", long)]
+    pub ack: bool,
+
+    /// Variable assignments of the form `<id>=<value>`, arrays are comma separated
+    #[structopt(name = "assignment", required = true)]
+    pub assignments: Vec<Assignment>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct CallOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Request an acknowledgement from the board for each callback
+    #[structopt(short = "a", long)]
+    pub ack: bool,
+
+    /// How long to keep listening for variables pushed by the board after each callback
+    #[structopt(short = "w", long, default_value = "250ms")]
+    pub wait: humantime::Duration,
+
+    /// Message IDs of the callbacks to invoke
+    #[structopt(name = "id", required = true)]
+    pub ids: Vec<OwnedMessageId>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct WatchOpts {
+    #[structopt(flatten)]
+    pub device: DeviceOpts,
+
+    /// Polling interval, e.g. 100ms or 2s
+    #[structopt(short = "i", long, default_value = "500ms")]
+    pub interval: humantime::Duration,
+
+    /// Don't poll, only listen for variables pushed by the board
+    #[structopt(long)]
+    pub listen: bool,
+
+    /// Only print variables when their value changes
+    #[structopt(short = "c", long)]
+    pub changes_only: bool,
+
+    /// Message IDs of the variables to watch, defaults to all tracked variables
+    #[structopt(name = "id")]
+    pub ids: Vec<OwnedMessageId>,
 }
 
 #[derive(Debug, Clone, PartialEq, StructOpt)]
 pub struct DeviceOpts {
-    /// Serial device baud rate
+    /// Serial device baud rate, parameters in a serial:// device URI take precedence
     #[structopt(short = "b", long, default_value = "115200")]
     pub baud_rate: u32,
 
@@ -38,9 +346,30 @@ pub struct DeviceOpts {
     #[structopt(long, default_value = "1")]
     pub stop_bits: StopBits,
 
-    /// Serial device path
+    /// How long to wait for each response from the device, e.g. 500ms
+    #[structopt(long, default_value = "1s")]
+    pub timeout: humantime::Duration,
+
+    /// Number of times a request is re-sent after timing out
+    #[structopt(long, default_value = "2")]
+    pub retries: usize,
+
+    /// Send a heartbeat at this interval to monitor the link health, e.g. 1s
+    #[structopt(long)]
+    pub heartbeat: Option<humantime::Duration>,
+
+    /// Number of consecutive missed heartbeats before the link is considered down
+    #[structopt(long, default_value = "3")]
+    pub heartbeat_misses: usize,
+
+    /// Don't read or write the cached board schemas, requesting variable types from the board
+    #[structopt(long)]
+    pub no_schema_cache: bool,
+
+    /// Device path or URI, e.g. /dev/ttyUSB0, serial:///dev/ttyUSB0?baud=115200,
+    /// tcp://host:port, unix:///path/sock or sim://path/schema.toml
     #[structopt(name = "device")]
-    pub device: String,
+    pub device: DeviceUri,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -106,3 +435,169 @@ impl FromStr for StopBits {
         }))
     }
 }
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VariableType(pub MessageType);
+
+impl FromStr for VariableType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(match s.trim().to_lowercase().as_str() {
+            "byte" => MessageType::Byte,
+            "char" | "str" | "string" => MessageType::Char,
+            "i8" => MessageType::I8,
+            "u8" => MessageType::U8,
+            "i16" => MessageType::I16,
+            "u16" => MessageType::U16,
+            "i32" => MessageType::I32,
+            "u32" => MessageType::U32,
+            "f32" | "float" => MessageType::F32,
+            "f64" | "double" => MessageType::F64,
+            _ => return Err("Invalid variable type".to_string()),
+        }))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Acknum(pub u8);
+
+impl FromStr for Acknum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse() {
+            Ok(acknum) if acknum <= MAX_ACKNUM => Ok(Self(acknum)),
+            _ => Err(format!("Invalid acknum '{s}', expected 0 to {MAX_ACKNUM}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffTarget {
+    Device(DeviceUri),
+    Snapshot(PathBuf),
+}
+
+impl FromStr for DiffTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.to_lowercase().ends_with(".json") {
+            Ok(DiffTarget::Snapshot(PathBuf::from(s)))
+        } else {
+            s.parse().map(DiffTarget::Device)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub id: OwnedMessageId,
+    pub value: String,
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, value) = s
+            .split_once('=')
+            .ok_or_else(|| "Assignments must be of the form <id>=<value>".to_string())?;
+        Ok(Self {
+            id: id.trim().parse().map_err(|e| format!("{e}"))?,
+            value: value.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Speed(pub f64);
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let speed: f64 = s
+            .strip_suffix('x')
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| format!("Invalid speed '{s}', expected a multiplier like 2x"))?;
+        if speed.is_finite() && speed > 0.0 {
+            Ok(Self(speed))
+        } else {
+            Err(format!("Speed '{s}' must be positive"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_assignments() {
+        assert_eq!(
+            "lit_time=250".parse(),
+            Ok(Assignment {
+                id: OwnedMessageId::new(b"lit_time").unwrap(),
+                value: "250".to_string(),
+            })
+        );
+        assert_eq!(
+            " wave =1, 2=3".parse(),
+            Ok(Assignment {
+                id: OwnedMessageId::new(b"wave").unwrap(),
+                value: "1, 2=3".to_string(),
+            })
+        );
+        assert!("lit_time".parse::<Assignment>().is_err());
+        assert!("=250".parse::<Assignment>().is_err());
+        assert!("a_message_id_that_is_too_long=1"
+            .parse::<Assignment>()
+            .is_err());
+    }
+
+    #[test]
+    fn encode_type_requires_a_value() {
+        let encode = |args: &[&str]| {
+            EncodeOpts::from_iter_safe(["encode", "--id", "led"].iter().chain(args))
+        };
+        assert!(encode(&[]).is_ok());
+        assert!(encode(&["--type", "u8", "--value", "1"]).is_ok());
+        assert!(encode(&["--type", "u8"]).is_err());
+        assert!(encode(&["--value", "1"]).is_err());
+    }
+
+    #[test]
+    fn record_is_global() {
+        for args in [
+            &[
+                "electricui",
+                "--record",
+                "s.euicap",
+                "check",
+                "/dev/ttyUSB0",
+            ][..],
+            &[
+                "electricui",
+                "check",
+                "/dev/ttyUSB0",
+                "--record",
+                "s.euicap",
+            ],
+            &[
+                "electricui",
+                "decode",
+                "--record",
+                "s.euicap",
+                "--hex",
+                "00",
+            ],
+        ] {
+            let opts = Opts::from_iter_safe(args).unwrap();
+            assert_eq!(opts.record, Some(PathBuf::from("s.euicap")));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use electricui_cli::codec::FrameEntry;
+use electricui_cli::types::{Variable, VariableKind};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Yaml,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "yaml" => Format::Yaml,
+            "csv" => Format::Csv,
+            _ => return Err("Invalid output format".to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum OutputError {
+    #[error("Encountered an IO error while writing output")]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_norway::Error),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+}
+
+/// Something a command outputs, in any of the supported formats
+pub trait Render: Serialize {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()>;
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["key", "value"]
+    }
+
+    /// Defaults to the serialized form flattened into dotted-path key/value rows
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten("", &value, &mut rows);
+        }
+        rows
+    }
+}
+
+/// Writes each rendered item to stdout.
+///
+/// JSON is written one document per line, YAML as a stream of documents
+/// and CSV with a header row before the first item and whenever the columns change.
+#[derive(Debug)]
+pub struct Output {
+    format: Format,
+    csv_header: Option<Vec<&'static str>>,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            csv_header: None,
+        }
+    }
+
+    pub fn emit<R: Render>(&mut self, item: &R) -> Result<(), OutputError> {
+        let stdout = io::stdout();
+        let mut w = stdout.lock();
+        self.write(&mut w, item)
+    }
+
+    fn write<R: Render>(&mut self, mut w: &mut dyn Write, item: &R) -> Result<(), OutputError> {
+        match self.format {
+            Format::Text => item.render_text(w)?,
+            Format::Json => {
+                serde_json::to_writer(&mut w, item)?;
+                writeln!(w)?;
+            }
+            Format::Yaml => {
+                writeln!(w, "---")?;
+                serde_norway::to_writer(&mut w, item)?;
+            }
+            Format::Csv => {
+                let mut csv = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(&mut w);
+                let header = item.csv_header();
+                if self.csv_header.as_ref() != Some(&header) {
+                    csv.write_record(&header)?;
+                    self.csv_header = Some(header);
+                }
+                for row in item.csv_rows() {
+                    csv.write_record(row)?;
+                }
+                csv.flush()?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+impl Render for Variable {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", self)
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["id", "type", "value"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        vec![variable_csv_row(self)]
+    }
+}
+
+impl Render for FrameEntry {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut parts = Vec::new();
+        parts.extend(self.timestamp.clone());
+        parts.extend(self.direction.map(|d| d.to_string()));
+        if let (Some(id), Some(typ)) = (&self.id, &self.typ) {
+            let flags: Vec<&str> = [
+                (self.internal, "internal"),
+                (self.offset.is_some(), "offset"),
+                (self.response, "response"),
+            ]
+            .iter()
+            .filter_map(|(set, name)| set.then_some(*name))
+            .collect();
+            parts.push(format!(
+                "'{id}' {typ} [{}] ack={}",
+                flags.join(","),
+                self.acknum
+            ));
+            if let (Some(len), Some(crc)) = (self.data_length, self.checksum) {
+                parts.push(format!("len={len} crc=0x{crc:04X}"));
+            }
+        }
+        parts.extend(self.offset.map(|offset| format!("@{offset}")));
+        parts.extend(
+            self.value
+                .as_ref()
+                .map(|v| format!("= {}", kind_value_string(v))),
+        );
+        parts.extend(self.note.as_ref().map(|note| format!("({note})")));
+        parts.extend(self.error.as_ref().map(|error| format!("ERROR: {error}")));
+        writeln!(w, "{}", parts.join(" "))
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec![
+            "timestamp",
+            "direction",
+            "id",
+            "type",
+            "internal",
+            "offset",
+            "response",
+            "acknum",
+            "data_length",
+            "checksum",
+            "value",
+            "note",
+            "error",
+        ]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.timestamp.clone().unwrap_or_default(),
+            self.direction.map(|d| d.to_string()).unwrap_or_default(),
+            self.id.clone().unwrap_or_default(),
+            self.typ.clone().unwrap_or_default(),
+            self.internal.to_string(),
+            self.offset.map(|o| o.to_string()).unwrap_or_default(),
+            self.response.to_string(),
+            self.acknum.to_string(),
+            self.data_length.map(|l| l.to_string()).unwrap_or_default(),
+            self.checksum
+                .map(|c| format!("0x{c:04X}"))
+                .unwrap_or_default(),
+            self.value
+                .as_ref()
+                .map(kind_value_string)
+                .unwrap_or_default(),
+            self.note.clone().unwrap_or_default(),
+            self.error.clone().unwrap_or_default(),
+        ]]
+    }
+}
+
+/// The id, type and value columns of a variable
+pub fn variable_csv_row(var: &Variable) -> Vec<String> {
+    vec![
+        var.id.to_string(),
+        format!("{}", var.kind.message_type()),
+        kind_value_string(&var.kind),
+    ]
+}
+
+/// The value of a variable without its type, as it is serialized
+pub fn kind_value_string(kind: &VariableKind) -> String {
+    match serde_json::to_value(kind) {
+        Ok(serde_json::Value::Object(mut o)) => match o.remove("value") {
+            Some(serde_json::Value::String(s)) => s,
+            Some(v) => v.to_string(),
+            None => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+fn flatten(prefix: &str, value: &serde_json::Value, rows: &mut Vec<Vec<String>>) {
+    use serde_json::Value;
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_owned()
+        } else {
+            format!("{prefix}.{k}")
+        }
+    };
+    match value {
+        Value::Object(o) => {
+            for (k, v) in o.iter() {
+                flatten(&key(k), v, rows);
+            }
+        }
+        Value::Array(a) => {
+            for (idx, v) in a.iter().enumerate() {
+                flatten(&key(&idx.to_string()), v, rows);
+            }
+        }
+        Value::String(s) => rows.push(vec![prefix.to_owned(), s.clone()]),
+        Value::Null => rows.push(vec![prefix.to_owned(), String::new()]),
+        v => rows.push(vec![prefix.to_owned(), v.to_string()]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use electricui_cli::types::OwnedMessageId;
+    use pretty_assertions::assert_eq;
+
+    #[derive(Serialize)]
+    struct Status {
+        ok: bool,
+    }
+
+    impl Render for Status {
+        fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+            writeln!(w, "{}", self.ok)
+        }
+    }
+
+    #[test]
+    fn csv_header_follows_the_columns() {
+        let var = |v| Variable {
+            id: OwnedMessageId::new(b"led").unwrap(),
+            kind: VariableKind::U8(v),
+        };
+        let mut output = Output::new(Format::Csv);
+        let mut w = Vec::new();
+        output.write(&mut w, &var(1)).unwrap();
+        output.write(&mut w, &var(2)).unwrap();
+        output.write(&mut w, &Status { ok: true }).unwrap();
+        output.write(&mut w, &var(3)).unwrap();
+        assert_eq!(
+            String::from_utf8(w).unwrap(),
+            "id,type,value\nled,U8,1\nled,U8,2\nkey,value\nok,true\nid,type,value\nled,U8,3\n"
+        );
+    }
+}
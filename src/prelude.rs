@@ -0,0 +1,8 @@
+pub use crate::client::{Client, ClientError, MAX_PACKET_SIZE};
+pub use crate::compare::{Delta, VariableChange};
+pub use crate::heartbeat::{HeartbeatMonitor, HeartbeatStats, LinkEvent};
+pub use crate::link::{LinkConfig, LinkError};
+pub use crate::offset::{OffsetMetadata, Reassembler};
+pub use crate::schema::{BoardSchema, SchemaCache, SchemaChange, VariableSchema};
+pub use crate::transport::{BoxedTransport, Transport};
+pub use crate::types::*;
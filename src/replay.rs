@@ -0,0 +1,367 @@
+use crate::device;
+use crate::opts::ReplayOpts;
+use crate::output::{Format, Output, Render};
+use bytes::Bytes;
+use electricui_cli::capture::{self, Direction, Record};
+use electricui_cli::link::Link;
+use electricui_cli::prelude::*;
+use electricui_embedded::prelude::*;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+pub async fn replay(
+    opts: ReplayOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let records = capture::Reader::open(&opts.capture)?.collect::<Result<Vec<_>, _>>()?;
+    let steps = steps(&records);
+    info!(
+        "Replaying {} requests from '{}'",
+        steps.len(),
+        opts.capture.display()
+    );
+
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut link = Link::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+    let timeout: Duration = opts.device.timeout.into();
+
+    // Recorded timestamps, scaled by the speed, relative to the first request
+    let start = Instant::now();
+    let first = steps.first().map(|s| s.timestamp).unwrap_or_default();
+    let send_at = |step: &Step| start + (step.timestamp - first).div_f64(opts.speed.0);
+
+    let mut report = ReplayReport::default();
+    for (idx, step) in steps.iter().enumerate() {
+        if !opts.as_fast_as_possible {
+            time::sleep_until(send_at(step)).await;
+        }
+        debug!("Replaying step {idx}, {}", step.request);
+        link.send(Packet::new_unchecked(step.request.as_ref()))
+            .await?;
+
+        let mut replies = Vec::new();
+        let deadline = Instant::now() + timeout;
+        while replies.len() < step.responses.len() {
+            match time::timeout_at(deadline, link.next()).await {
+                Ok(pkt) => replies.push(pkt?),
+                Err(_) => break,
+            }
+        }
+        // Anything else arriving before the next request was also a reply to this one
+        if let (false, Some(next)) = (opts.as_fast_as_possible, steps.get(idx + 1)) {
+            while let Ok(pkt) = time::timeout_at(send_at(next), link.next()).await {
+                replies.push(pkt?);
+            }
+        }
+        report.compare(idx, step, &replies);
+    }
+
+    output.emit(&report)?;
+    if !report.divergences.is_empty() {
+        return Err(ReplayDivergedError(report.divergences.len()).into());
+    }
+    Ok(())
+}
+
+/// A recorded request and the responses recorded before the next request
+struct Step {
+    timestamp: Duration,
+    request: Packet<Bytes>,
+    responses: Vec<Packet<Bytes>>,
+}
+
+fn steps(records: &[Record]) -> Vec<Step> {
+    let mut steps: Vec<Step> = Vec::new();
+    for (idx, record) in records.iter().enumerate() {
+        if let Some(e) = &record.error {
+            warn!("Skipping record {idx}, recorded with a decoder error. {e}");
+            continue;
+        }
+        let pkt = match record.packet() {
+            Ok(Some(pkt)) => pkt,
+            Ok(None) => {
+                warn!("Skipping record {idx}, it doesn't contain a complete packet");
+                continue;
+            }
+            Err(e) => {
+                warn!("Skipping record {idx}, it failed to decode. {e}");
+                continue;
+            }
+        };
+        match (record.direction, steps.last_mut()) {
+            (Direction::Tx, _) => steps.push(Step {
+                timestamp: record.timestamp,
+                request: pkt,
+                responses: Vec::new(),
+            }),
+            (Direction::Rx, Some(step)) => step.responses.push(pkt),
+            (Direction::Rx, None) => debug!("Ignoring record {idx} received before any request"),
+        }
+    }
+    steps
+}
+
+fn describe(pkt: &Packet<Bytes>) -> String {
+    if !pkt.internal() {
+        if let Ok(var) = Variable::decode_response(pkt) {
+            return var.to_string();
+        }
+    }
+    pkt.to_string()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    pub requests: usize,
+    pub responses_expected: usize,
+    pub responses_received: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DivergenceKind {
+    /// A recorded response was not received
+    Missing,
+    /// A response was received that wasn't recorded
+    Unexpected,
+    /// The response differs from the recorded one
+    Changed,
+}
+
+impl fmt::Display for DivergenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DivergenceKind::Missing => f.write_str("missing"),
+            DivergenceKind::Unexpected => f.write_str("unexpected"),
+            DivergenceKind::Changed => f.write_str("changed"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Divergence {
+    pub step: usize,
+    pub request: String,
+    pub kind: DivergenceKind,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl ReplayReport {
+    fn compare(&mut self, idx: usize, step: &Step, replies: &[Packet<Bytes>]) {
+        self.requests += 1;
+        self.responses_expected += step.responses.len();
+        self.responses_received += replies.len();
+        let request = step
+            .request
+            .msg_id()
+            .map(|id| OwnedMessageId::from_wire(&id).to_string())
+            .unwrap_or_else(|_| step.request.to_string());
+        for i in 0..step.responses.len().max(replies.len()) {
+            let (expected, actual) = (step.responses.get(i), replies.get(i));
+            let kind = match (expected, actual) {
+                (Some(e), Some(a)) if e.as_ref() == a.as_ref() => continue,
+                (Some(_), Some(_)) => DivergenceKind::Changed,
+                (Some(_), None) => DivergenceKind::Missing,
+                (None, _) => DivergenceKind::Unexpected,
+            };
+            self.divergences.push(Divergence {
+                step: idx,
+                request: request.clone(),
+                kind,
+                expected: expected.map(describe),
+                actual: actual.map(describe),
+            });
+        }
+    }
+}
+
+impl Render for ReplayReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "Replayed {} requests, received {}/{} responses, {} divergence(s)",
+            self.requests,
+            self.responses_received,
+            self.responses_expected,
+            self.divergences.len()
+        )?;
+        for d in self.divergences.iter() {
+            write!(w, "  Step {} '{}' {}", d.step, d.request, d.kind)?;
+            if let Some(e) = &d.expected {
+                write!(w, ", expected {e}")?;
+            }
+            if let Some(a) = &d.actual {
+                write!(w, ", got {a}")?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["step", "request", "kind", "expected", "actual"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.divergences
+            .iter()
+            .map(|d| {
+                vec![
+                    d.step.to_string(),
+                    d.request.clone(),
+                    d.kind.to_string(),
+                    d.expected.clone().unwrap_or_default(),
+                    d.actual.clone().unwrap_or_default(),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Replay diverged from the capture in {0} response(s)")]
+pub struct ReplayDivergedError(pub usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use electricui_cli::codec::Encoder;
+    use pretty_assertions::assert_eq;
+    use tokio_util::codec::Encoder as _;
+
+    fn packet(id: &[u8], kind: VariableKind) -> Packet<Bytes> {
+        let var = Variable {
+            id: OwnedMessageId::new(id).unwrap(),
+            kind,
+        };
+        Packet::new_unchecked(var.encode_requests(false, 0).unwrap().remove(0).into())
+    }
+
+    fn record(ms: u64, direction: Direction, pkt: &Packet<Bytes>) -> Record {
+        let mut frame = BytesMut::new();
+        Encoder::default()
+            .encode(Packet::new_unchecked(pkt.as_ref()), &mut frame)
+            .unwrap();
+        Record {
+            timestamp: Duration::from_millis(ms),
+            direction,
+            frame: frame.to_vec(),
+            error: None,
+        }
+    }
+
+    fn summary(steps: &[Step]) -> Vec<(u64, String, Vec<String>)> {
+        steps
+            .iter()
+            .map(|s| {
+                (
+                    s.timestamp.as_millis() as u64,
+                    describe(&s.request),
+                    s.responses.iter().map(describe).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steps_group_responses_by_request() {
+        let led = packet(b"led", VariableKind::U8(1));
+        let name = packet(b"name", VariableKind::U8(2));
+        let mut corrupt = record(4, Direction::Rx, &led);
+        corrupt.error = Some("Invalid checksum".to_owned());
+        let records = vec![
+            record(0, Direction::Rx, &name),
+            record(1, Direction::Tx, &led),
+            record(2, Direction::Rx, &led),
+            corrupt,
+            Record {
+                timestamp: Duration::from_millis(5),
+                direction: Direction::Rx,
+                frame: vec![0x05, 0x01],
+                error: None,
+            },
+            record(6, Direction::Tx, &name),
+            record(7, Direction::Rx, &name),
+            record(8, Direction::Rx, &name),
+        ];
+        assert_eq!(
+            summary(&steps(&records)),
+            vec![
+                (1, describe(&led), vec![describe(&led)]),
+                (6, describe(&name), vec![describe(&name), describe(&name)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_reports_divergences() {
+        let req = packet(b"led", VariableKind::U8(0));
+        let a = packet(b"led", VariableKind::U8(1));
+        let b = packet(b"led", VariableKind::U8(2));
+        let c = packet(b"led", VariableKind::U8(3));
+        let step = Step {
+            timestamp: Duration::ZERO,
+            request: req,
+            responses: vec![a.clone(), b.clone()],
+        };
+
+        let mut report = ReplayReport::default();
+        report.compare(0, &step, &[a.clone(), b.clone()]);
+        report.compare(1, &step, std::slice::from_ref(&a));
+        report.compare(2, &step, &[a.clone(), c.clone()]);
+        report.compare(3, &step, &[a.clone(), b.clone(), c.clone()]);
+
+        assert_eq!(report.requests, 4);
+        assert_eq!(report.responses_expected, 8);
+        assert_eq!(report.responses_received, 8);
+        let divergences: Vec<_> = report
+            .divergences
+            .iter()
+            .map(|d| {
+                (
+                    d.step,
+                    d.request.as_str(),
+                    d.kind,
+                    d.expected.clone(),
+                    d.actual.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            divergences,
+            vec![
+                (1, "led", DivergenceKind::Missing, Some(describe(&b)), None),
+                (
+                    2,
+                    "led",
+                    DivergenceKind::Changed,
+                    Some(describe(&b)),
+                    Some(describe(&c))
+                ),
+                (
+                    3,
+                    "led",
+                    DivergenceKind::Unexpected,
+                    None,
+                    Some(describe(&c))
+                ),
+            ]
+        );
+    }
+}
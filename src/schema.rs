@@ -0,0 +1,420 @@
+//! Board schemas, the message IDs and types of a board's variables without their values.
+//!
+//! Schemas are cached on disk as TOML, one file per [`BoardId`] and [`BoardName`],
+//! so commands can learn variable types without requesting every tracked variable.
+//! A cached schema is stale once the board announces a different number of writable IDs.
+//!
+//! Exported schemas double as lock files, [`BoardSchema::diff`] reports how a board
+//! drifted from one.
+
+use crate::error::ParseVariableError;
+use crate::types::{
+    BoardId, BoardName, IdsAnnouncement, OwnedMessageId, TrackedVariables, Variable, VariableKind,
+};
+use electricui_embedded::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+use thiserror::Error;
+use tracing::debug;
+
+/// File extension of cached schemas
+pub const EXTENSION: &str = "toml";
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("Encountered an IO error while reading or writing the schema")]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    De(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Ser(#[from] toml::ser::Error),
+}
+
+/// The type and shape of a variable
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct VariableSchema {
+    pub id: OwnedMessageId,
+    #[serde(rename = "type", with = "message_type")]
+    pub typ: MessageType,
+    #[serde(default)]
+    pub array: bool,
+    /// Number of elements of arrays, not compared when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<usize>,
+}
+
+impl fmt::Display for VariableSchema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.array, self.len) {
+            (true, Some(len)) => write!(f, "{}[{len}]", self.typ),
+            (true, None) => write!(f, "{}[]", self.typ),
+            (false, _) => write!(f, "{}", self.typ),
+        }
+    }
+}
+
+impl VariableSchema {
+    pub fn of(var: &Variable) -> Self {
+        Self {
+            id: var.id.clone(),
+            typ: var.kind.message_type(),
+            array: var.kind.is_array(),
+            len: var.kind.array_len(),
+        }
+    }
+
+    /// Whether a variable of type `actual` satisfies this one
+    pub fn accepts(&self, actual: &VariableSchema) -> bool {
+        self.typ == actual.typ
+            && self.array == actual.array
+            && (self.len.is_none() || self.len == actual.len)
+    }
+
+    /// Parses the textual representation of a value of this variable's type and shape
+    pub fn parse(&self, s: &str) -> Result<VariableKind, ParseVariableError> {
+        if self.typ == MessageType::Callback {
+            return Ok(VariableKind::Callback);
+        }
+        VariableKind::parse(self.typ, Some(self.array), s)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BoardSchema {
+    pub board_id: BoardId,
+    pub board_name: BoardName,
+    /// Number of IDs in the writable IDs announcement when the schema was taken
+    pub ids_count: usize,
+    #[serde(default)]
+    pub variables: Vec<VariableSchema>,
+}
+
+impl BoardSchema {
+    pub fn new(
+        board_id: BoardId,
+        board_name: BoardName,
+        ids: &IdsAnnouncement,
+        variables: &TrackedVariables,
+    ) -> Self {
+        Self {
+            board_id,
+            board_name,
+            ids_count: ids.len(),
+            variables: variables
+                .as_slice()
+                .iter()
+                .map(VariableSchema::of)
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, id: &OwnedMessageId) -> Option<&VariableSchema> {
+        self.variables.iter().find(|v| &v.id == id)
+    }
+
+    /// Differences of `actual` from this, the expected schema.
+    ///
+    /// Removed and retyped variables are listed in this schema's order, followed by
+    /// added variables in `actual`'s order.
+    pub fn diff(&self, actual: &BoardSchema) -> Vec<SchemaChange> {
+        let mut changes = Vec::new();
+        if self.board_id != actual.board_id {
+            changes.push(SchemaChange::BoardId {
+                expected: self.board_id,
+                actual: actual.board_id,
+            });
+        }
+        if self.board_name != actual.board_name {
+            changes.push(SchemaChange::BoardName {
+                expected: self.board_name.clone(),
+                actual: actual.board_name.clone(),
+            });
+        }
+        for expected in self.variables.iter() {
+            match actual.get(&expected.id) {
+                None => changes.push(SchemaChange::Removed(expected.clone())),
+                Some(var) if !expected.accepts(var) => changes.push(SchemaChange::Retyped {
+                    expected: expected.clone(),
+                    actual: var.clone(),
+                }),
+                Some(_) => (),
+            }
+        }
+        for var in actual.variables.iter() {
+            if self.get(&var.id).is_none() {
+                changes.push(SchemaChange::Added(var.clone()));
+            }
+        }
+        changes
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, SchemaError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, SchemaError> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// A difference between an expected and an actual board schema
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum SchemaChange {
+    BoardId {
+        expected: BoardId,
+        actual: BoardId,
+    },
+    BoardName {
+        expected: BoardName,
+        actual: BoardName,
+    },
+    /// A variable the expected schema doesn't have
+    Added(VariableSchema),
+    /// An expected variable the board doesn't have
+    Removed(VariableSchema),
+    /// A variable whose type, shape or length differs
+    Retyped {
+        expected: VariableSchema,
+        actual: VariableSchema,
+    },
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaChange::BoardId { expected, actual } => {
+                write!(f, "~ board ID 0x{actual:04X}, expected 0x{expected:04X}")
+            }
+            SchemaChange::BoardName { expected, actual } => {
+                write!(f, "~ board name '{actual}', expected '{expected}'")
+            }
+            SchemaChange::Added(var) => write!(f, "+ {} {var}", var.id),
+            SchemaChange::Removed(var) => write!(f, "- {} {var}", var.id),
+            SchemaChange::Retyped { expected, actual } => {
+                write!(f, "~ {} {actual}, expected {expected}", actual.id)
+            }
+        }
+    }
+}
+
+/// A directory of cached board schemas
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SchemaCache {
+    dir: PathBuf,
+}
+
+impl SchemaCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// electricui/schemas in the user's cache directory, if there is one
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("electricui").join("schemas"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the cached schema of a board, if there is one
+    pub fn load(
+        &self,
+        board_id: BoardId,
+        board_name: &BoardName,
+    ) -> Result<Option<BoardSchema>, SchemaError> {
+        let path = self.path(board_id, board_name);
+        let s = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let schema = BoardSchema::from_toml(&s)?;
+        // Guard against colliding file names
+        if schema.board_id != board_id || &schema.board_name != board_name {
+            return Ok(None);
+        }
+        debug!("Loaded cached schema '{}'", path.display());
+        Ok(Some(schema))
+    }
+
+    pub fn store(&self, schema: &BoardSchema) -> Result<(), SchemaError> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(schema.board_id, &schema.board_name);
+        fs::write(&path, schema.to_toml()?)?;
+        debug!("Stored schema '{}'", path.display());
+        Ok(())
+    }
+
+    fn path(&self, board_id: BoardId, board_name: &BoardName) -> PathBuf {
+        let name: String = board_name
+            .to_string()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir
+            .join(format!("{board_id:04X}-{name}"))
+            .with_extension(EXTENSION)
+    }
+}
+
+/// Message types by name, the inverse of their `Display` impl
+mod message_type {
+    use electricui_embedded::message::MessageType;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(typ: &MessageType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(typ)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<MessageType, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        (0..=u8::MAX)
+            .map(MessageType::from)
+            .find(|typ| typ.to_string() == s)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid message type '{s}'")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A directory under the temp dir, removed when dropped even if the test panics
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("{name}-{}", std::process::id())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let schema = BoardSchema {
+            board_id: 0xBEEF.into(),
+            board_name: b"my board".to_vec().into(),
+            ids_count: 2,
+            variables: vec![
+                VariableSchema {
+                    id: OwnedMessageId::new(b"lit_time").unwrap(),
+                    typ: MessageType::U16,
+                    array: false,
+                    len: None,
+                },
+                VariableSchema {
+                    id: OwnedMessageId::new(b"wave").unwrap(),
+                    typ: MessageType::F32,
+                    array: true,
+                    len: Some(4),
+                },
+            ],
+        };
+        let dir = TempDir::new("electricui-schema-test");
+        let cache = SchemaCache::new(dir.0.clone());
+        assert_eq!(
+            cache.load(schema.board_id, &schema.board_name).unwrap(),
+            None
+        );
+        cache.store(&schema).unwrap();
+        assert_eq!(
+            cache.load(schema.board_id, &schema.board_name).unwrap(),
+            Some(schema.clone())
+        );
+        assert_eq!(
+            cache
+                .load(schema.board_id, &b"other".to_vec().into())
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            schema.variables[1].parse("1.5, 2").unwrap(),
+            VariableKind::F32Array(vec![1.5.into(), 2.0.into()])
+        );
+    }
+
+    #[test]
+    fn diff_against_lock_file() {
+        let expected = BoardSchema::from_toml(
+            r#"
+            board_id = 48879
+            board_name = "demo"
+            ids_count = 3
+
+            [[variables]]
+            id = "led"
+            type = "U16"
+
+            [[variables]]
+            id = "big"
+            type = "U8"
+            array = true
+            len = 3
+
+            [[variables]]
+            id = "gone"
+            type = "Callback"
+            "#,
+        )
+        .unwrap();
+        let var = |id: &str, kind| {
+            VariableSchema::of(&Variable {
+                id: OwnedMessageId::from_utf8(id),
+                kind,
+            })
+        };
+        let mut actual = BoardSchema {
+            variables: vec![
+                var("led", VariableKind::U16(1)),
+                var("big", VariableKind::U8Array(vec![1, 2, 3])),
+                var("gone", VariableKind::Callback),
+            ],
+            ..expected.clone()
+        };
+        assert_eq!(expected.diff(&actual), vec![]);
+
+        actual.variables = vec![
+            var("new", VariableKind::F32(1.0.into())),
+            var("big", VariableKind::U8Array(vec![1, 2, 3, 4])),
+            var("led", VariableKind::U16(1)),
+        ];
+        actual.board_id = 0xF00D.into();
+        let changes = expected.diff(&actual);
+        assert_eq!(
+            changes,
+            vec![
+                SchemaChange::BoardId {
+                    expected: 0xBEEF.into(),
+                    actual: 0xF00D.into(),
+                },
+                SchemaChange::Retyped {
+                    expected: expected.variables[1].clone(),
+                    actual: actual.variables[1].clone(),
+                },
+                SchemaChange::Removed(expected.variables[2].clone()),
+                SchemaChange::Added(actual.variables[0].clone()),
+            ]
+        );
+        assert_eq!(changes[1].to_string(), "~ big U8[4], expected U8[3]");
+    }
+}
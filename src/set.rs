@@ -0,0 +1,99 @@
+use crate::device;
+use crate::opts::SetOpts;
+use crate::output::{Format, Output};
+use electricui_cli::prelude::*;
+use std::path::Path;
+use thiserror::Error;
+use tracing::warn;
+
+pub async fn set(
+    opts: SetOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+    let mut unacknowledged = Vec::new();
+    // Without a cache the schema costs a full handshake, more than getting each variable
+    let schema = match (opts.typ, device::schema_cache(&opts.device)) {
+        (None, Some(cache)) => Some(client.schema(Some(&cache)).await?),
+        _ => None,
+    };
+
+    for assignment in opts.assignments.iter() {
+        let id = &assignment.id;
+        let kind = match (opts.typ, schema.as_ref().and_then(|s| s.get(id))) {
+            (Some(typ), _) => VariableKind::parse(typ.0, None, &assignment.value)?,
+            (None, Some(var)) => var.parse(&assignment.value)?,
+            (None, None) => {
+                // Use the variable's current value as the template for its type and shape
+                let current = client.get(id).await?;
+                VariableKind::parse(
+                    current.kind.message_type(),
+                    Some(current.kind.is_array()),
+                    &assignment.value,
+                )?
+            }
+        };
+        let var = Variable {
+            id: id.clone(),
+            kind,
+        };
+
+        if opts.ack {
+            match client.set_acked(&var).await {
+                Ok(()) => (),
+                Err(e @ ClientError::Unacknowledged { .. }) => {
+                    warn!("{e}");
+                    unacknowledged.push(var.id);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            client.set(&var).await?;
+        }
+
+        if opts.verify {
+            let readback = client.get(id).await?;
+            if readback.kind != var.kind {
+                return Err(WriteVerificationError {
+                    expected: var,
+                    actual: readback,
+                }
+                .into());
+            }
+        }
+        output.emit(&var)?;
+    }
+
+    if !unacknowledged.is_empty() {
+        return Err(UnacknowledgedWritesError(unacknowledged).into());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+#[error("Variable read back as '{actual}' after writing '{expected}'")]
+pub struct WriteVerificationError {
+    pub expected: Variable,
+    pub actual: Variable,
+}
+
+#[derive(Debug, Error)]
+#[error("{} write(s) were never acknowledged: {}", .0.len(), join_ids(.0))]
+pub struct UnacknowledgedWritesError(pub Vec<OwnedMessageId>);
+
+fn join_ids(ids: &[OwnedMessageId]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
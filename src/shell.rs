@@ -0,0 +1,417 @@
+use crate::call;
+use crate::check::HeartbeatCheck;
+use crate::device;
+use crate::opts::{Assignment, ShellOpts};
+use crate::output::{Format, Output};
+use crate::watch::Sample;
+use electricui_cli::prelude::*;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, warn};
+
+const PROMPT: &str = "eui> ";
+
+type ShellEditor = Editor<ShellHelper, DefaultHistory>;
+
+const COMMANDS: &[&str] = &[
+    "get", "set", "call", "watch", "vars", "hb", "help", "exit", "quit",
+];
+
+/// Commands accepted at the prompt
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "",
+    usage = "<command> [args]",
+    no_version,
+    setting = AppSettings::NoBinaryName,
+    setting = AppSettings::DisableVersion,
+    setting = AppSettings::VersionlessSubcommands
+)]
+enum ShellCommand {
+    /// Read one or more variables by message ID
+    Get {
+        #[structopt(name = "id", required = true)]
+        ids: Vec<OwnedMessageId>,
+    },
+
+    /// Write one or more variables, e.g. lit_time=250
+    Set {
+        /// Request an acknowledgement for each write
+        #[structopt(short = "a", long)]
+        ack: bool,
+
+        #[structopt(name = "assignment", required = true)]
+        assignments: Vec<Assignment>,
+    },
+
+    /// Invoke one or more callbacks by message ID
+    Call {
+        /// Request an acknowledgement for each callback
+        #[structopt(short = "a", long)]
+        ack: bool,
+
+        /// How long to collect variables pushed by the board after each callback
+        #[structopt(short = "w", long, default_value = "250ms")]
+        wait: humantime::Duration,
+
+        #[structopt(name = "id", required = true)]
+        ids: Vec<OwnedMessageId>,
+    },
+
+    /// Poll variables a number of times, defaults to all tracked variables
+    Watch {
+        /// Polling interval, e.g. 100ms or 2s
+        #[structopt(short = "i", long, default_value = "500ms")]
+        interval: humantime::Duration,
+
+        /// Number of times to poll
+        #[structopt(short = "n", long, default_value = "10")]
+        count: usize,
+
+        #[structopt(name = "id")]
+        ids: Vec<OwnedMessageId>,
+    },
+
+    /// Read all tracked variables
+    Vars,
+
+    /// Send a heartbeat and check the board echoes it, values from 128 are reserved
+    /// while --heartbeat is enabled
+    Hb {
+        #[structopt(default_value = "1")]
+        value: u8,
+    },
+
+    /// Leave the shell
+    #[structopt(alias = "quit")]
+    Exit,
+}
+
+pub async fn shell(
+    opts: ShellOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    // Handshake once, every command reuses the IDs and variable types learned here
+    let schema = client
+        .schema(device::schema_cache(&opts.device).as_ref())
+        .await?;
+    println!(
+        "Connected to '{}' (0x{:04X}) with {} message IDs, 'help' lists the commands",
+        schema.board_name, schema.board_id, schema.ids_count
+    );
+    // Complete from the live announcement, the schema may have come from the cache
+    let ids = client.writable_ids().await?;
+    let mut editor = ShellEditor::new()?;
+    editor.set_helper(Some(ShellHelper {
+        ids: ids.as_slice().iter().map(|id| id.to_string()).collect(),
+    }));
+    let mut types: HashMap<OwnedMessageId, VariableSchema> = schema
+        .variables
+        .into_iter()
+        .map(|v| (v.id.clone(), v))
+        .collect();
+    let history = opts.history.clone().or_else(default_history_path);
+    if let Some(path) = &history {
+        if let Err(e) = editor.load_history(path) {
+            debug!("No history loaded from '{}'. {e}", path.display());
+        }
+    }
+
+    loop {
+        let (returned, line) = read_line(editor, &mut client).await?;
+        editor = returned;
+        let line = match line {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        let words = match split_words(line) {
+            Ok(words) => words,
+            Err(e) => {
+                println!("{e}");
+                continue;
+            }
+        };
+        let cmd = match ShellCommand::from_iter_safe(words) {
+            Ok(ShellCommand::Exit) => break,
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("{}", e.message);
+                continue;
+            }
+        };
+        // Each command's output stands alone, e.g. with its own CSV header
+        let mut output = Output::new(format);
+        // Failed commands are reported without closing the connection
+        if let Err(e) = run(cmd, &mut client, &mut types, &mut output).await {
+            println!("Error: {e}");
+        }
+    }
+
+    if let Some(path) = &history {
+        let dir = path.parent().map_or(Ok(()), fs::create_dir_all);
+        if let Err(e) = dir {
+            warn!("Failed to create the history directory. {e}");
+        } else if let Err(e) = editor.save_history(path) {
+            warn!("Failed to save the history to '{}'. {e}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reads a line on a blocking thread, servicing the link meanwhile so heartbeats keep
+/// going out and packets pushed by the board don't pile up behind the prompt
+async fn read_line<T: AsyncRead + AsyncWrite + Unpin, const N: usize>(
+    mut editor: ShellEditor,
+    client: &mut Client<'_, T, N>,
+) -> Result<(ShellEditor, rustyline::Result<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut readline = task::spawn_blocking(move || {
+        let line = editor.readline(PROMPT);
+        (editor, line)
+    });
+    let mut link_up = true;
+    loop {
+        tokio::select! {
+            res = &mut readline => return Ok(res?),
+            pkt = client.recv(), if link_up => match pkt {
+                Ok(pkt) => debug!("Discarding {pkt} received at the prompt"),
+                Err(e) => {
+                    println!("Error: {e}");
+                    link_up = false;
+                }
+            },
+        }
+    }
+}
+
+async fn run<T: AsyncRead + AsyncWrite + Unpin, const N: usize>(
+    cmd: ShellCommand,
+    client: &mut Client<'_, T, N>,
+    types: &mut HashMap<OwnedMessageId, VariableSchema>,
+    output: &mut Output,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match cmd {
+        ShellCommand::Get { ids } => {
+            for id in ids.iter() {
+                let var = client.get(id).await?;
+                types.insert(var.id.clone(), VariableSchema::of(&var));
+                output.emit(&var)?;
+            }
+        }
+        ShellCommand::Set { ack, assignments } => {
+            for assignment in assignments.iter() {
+                let id = &assignment.id;
+                let typ = match types.get(id) {
+                    Some(typ) => typ.clone(),
+                    None => VariableSchema::of(&client.get(id).await?),
+                };
+                let var = Variable {
+                    id: id.clone(),
+                    kind: typ.parse(&assignment.value)?,
+                };
+                if ack {
+                    client.set_acked(&var).await?;
+                } else {
+                    client.set(&var).await?;
+                }
+                types.insert(var.id.clone(), VariableSchema::of(&var));
+                output.emit(&var)?;
+            }
+        }
+        ShellCommand::Call { ack, wait, ids } => {
+            for id in ids.iter() {
+                output.emit(&call::call_one(client, id, ack, wait.into()).await?)?;
+            }
+        }
+        ShellCommand::Watch {
+            interval,
+            count,
+            ids,
+        } => {
+            let mut interval = time::interval(interval.into());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            for _ in 0..count {
+                interval.tick().await;
+                let vars = if ids.is_empty() {
+                    client.tracked_variables().await?.as_slice().to_vec()
+                } else {
+                    let mut vars = Vec::new();
+                    for id in ids.iter() {
+                        vars.push(client.get(id).await?);
+                    }
+                    vars
+                };
+                for var in vars.into_iter() {
+                    output.emit(&Sample {
+                        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                        variable: var,
+                    })?;
+                }
+            }
+        }
+        ShellCommand::Vars => {
+            for var in client.tracked_variables().await?.as_slice().iter() {
+                types.insert(var.id.clone(), VariableSchema::of(var));
+                output.emit(var)?;
+            }
+        }
+        ShellCommand::Hb { value } => {
+            let sent = Heartbeat::from(value);
+            let received = client.heartbeat(sent).await?;
+            output.emit(&HeartbeatCheck {
+                sent,
+                received,
+                matches: sent == received,
+            })?;
+        }
+        ShellCommand::Exit => (),
+    }
+    Ok(())
+}
+
+/// Splits a line into words like a POSIX shell, so values can contain spaces,
+/// e.g. `set name="bench 3"`.
+///
+/// Single quotes take everything literally, double quotes and unquoted words
+/// allow backslash escapes.
+fn split_words(line: &str) -> Result<Vec<String>, UnterminatedQuoteError> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or(UnterminatedQuoteError('\''))? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or(UnterminatedQuoteError('"'))? {
+                        '"' => break,
+                        '\\' => word.push(chars.next().ok_or(UnterminatedQuoteError('"'))?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Missing a closing {0} quote")]
+struct UnterminatedQuoteError(char);
+
+fn default_history_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("electricui").join("history"))
+}
+
+/// Completes command names, then the message IDs of the board's variables
+struct ShellHelper {
+    ids: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |idx| idx + 1);
+        let word = &line[start..pos];
+        let candidates: Vec<String> = if start == 0 {
+            COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| c.to_string())
+                .collect()
+        } else {
+            self.ids
+                .iter()
+                .filter(|id| id.starts_with(word))
+                .cloned()
+                .collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn split_quoted_words() {
+        let words = |line| split_words(line).unwrap();
+        assert_eq!(words("  get  led big "), vec!["get", "led", "big"]);
+        assert_eq!(
+            words(r#"set name="bench 3" note='a "b"' x=\ y"#),
+            vec!["set", "name=bench 3", r#"note=a "b""#, "x= y"]
+        );
+        assert_eq!(words(r#"set s="say \"hi\"""#), vec!["set", r#"s=say "hi""#]);
+        assert_eq!(words(r#"set s="""#), vec!["set", "s="]);
+        assert_eq!(words(""), Vec::<String>::new());
+        assert_eq!(
+            split_words(r#"set name="bench"#),
+            Err(UnterminatedQuoteError('"'))
+        );
+        assert_eq!(split_words("set s='x"), Err(UnterminatedQuoteError('\'')));
+    }
+}
@@ -0,0 +1,385 @@
+//! A simulated device, answering the same requests a board implementing
+//! the ElectricUI Binary Protocol would.
+//!
+//! The simulated board is described by a TOML schema file:
+//!
+//! ```toml
+//! board_id = 0xBEEF
+//! board_name = "my-board"
+//!
+//! [[variables]]
+//! id = "lit_time"
+//! type = "U16"
+//! value = 200
+//! ```
+
+use crate::codec::{self, Codec};
+use crate::error::{PacketError, PacketProtocolError};
+use crate::offset::{self, Reassembler};
+use crate::types::*;
+use bytes::Bytes;
+use electricui_embedded::prelude::*;
+use futures::stream::StreamExt;
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, PoisonError};
+use std::{fs, io, path::Path};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+/// Maximum number of IDs per writable IDs announcement packet
+const IDS_PER_ANNOUNCEMENT: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum SimError {
+    #[error("Encountered an IO error while reading the schema file")]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Schema(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Codec(#[from] codec::Error),
+
+    #[error(transparent)]
+    PacketProtocol(#[from] PacketProtocolError),
+}
+
+impl From<PacketError> for SimError {
+    fn from(e: PacketError) -> Self {
+        PacketProtocolError::from(e).into()
+    }
+}
+
+impl From<electricui_embedded::wire::packet::Error> for SimError {
+    fn from(e: electricui_embedded::wire::packet::Error) -> Self {
+        PacketError(e).into()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Schema {
+    pub board_id: BoardId,
+    pub board_name: String,
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+}
+
+impl Schema {
+    pub fn from_toml(s: &str) -> Result<Self, SimError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SimError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Simulator {
+    board_id: BoardId,
+    variables: Vec<Variable>,
+    reassembler: Reassembler,
+}
+
+impl Simulator {
+    pub fn new(schema: Schema) -> Self {
+        let mut variables = schema.variables;
+        // The board name is queried like any other variable
+        let name_id = OwnedMessageId::from_wire(&MessageId::BOARD_NAME);
+        if !variables.iter().any(|v| v.id == name_id) {
+            variables.push(Variable {
+                id: name_id,
+                kind: VariableKind::CharArray(schema.board_name),
+            });
+        }
+        Self {
+            board_id: schema.board_id,
+            variables,
+            reassembler: Reassembler::default(),
+        }
+    }
+
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    /// Answers requests on `io` until the host disconnects
+    pub async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        io: T,
+    ) -> Result<(), SimError> {
+        serve_with(io, |pkt| self.handle(pkt)).await
+    }
+
+    /// Like [`Simulator::serve`], for a simulator shared by concurrent connections.
+    /// The lock is only held while handling each request.
+    pub async fn serve_shared<T: AsyncRead + AsyncWrite + Unpin>(
+        sim: &Mutex<Self>,
+        io: T,
+    ) -> Result<(), SimError> {
+        serve_with(io, |pkt| {
+            sim.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .handle(pkt)
+        })
+        .await
+    }
+
+    /// Returns the encoded reply packets for a request
+    pub fn handle(&mut self, pkt: &Packet<Bytes>) -> Result<Vec<Vec<u8>>, SimError> {
+        let id = OwnedMessageId::from_wire(&pkt.msg_id()?);
+        debug!("Simulator received '{id}' {pkt}");
+
+        if pkt.internal() {
+            let id = id.as_wire();
+            return if id == MessageId::INTERNAL_BOARD_ID {
+                let board_id: u16 = self.board_id.into();
+                Ok(vec![reply(
+                    id,
+                    true,
+                    MessageType::U16,
+                    &board_id.to_le_bytes(),
+                )?])
+            } else if id == MessageId::INTERNAL_HEARTBEAT {
+                Ok(vec![reply(id, true, MessageType::U8, pkt.payload()?)?])
+            } else if id == MessageId::INTERNAL_AM {
+                self.announce_ids()
+            } else if id == MessageId::INTERNAL_AV {
+                let mut replies = Vec::new();
+                for var in self.variables.iter() {
+                    replies.extend(reply_variable(var, 0)?);
+                }
+                Ok(replies)
+            } else {
+                warn!("Simulator ignoring unsupported internal message '{id}'");
+                Ok(Vec::new())
+            };
+        }
+
+        let var = match self.variables.iter_mut().find(|v| v.id == id) {
+            Some(v) => v,
+            None => {
+                warn!("Simulator ignoring unknown message ID '{id}'");
+                return Ok(Vec::new());
+            }
+        };
+
+        // Large writes arrive as offset packets
+        let written = if pkt.offset() || pkt.typ() == MessageType::OffsetMetadata {
+            match self.reassembler.push(pkt)? {
+                Some(v) => Some(v.kind),
+                None => return Ok(Vec::new()),
+            }
+        } else if pkt.data_length() != 0 {
+            Some(VariableKind::from_wire(pkt.typ(), pkt.payload()?)?)
+        } else {
+            None
+        };
+
+        if var.kind.is_callback() {
+            info!("Simulator callback '{id}' invoked");
+        } else if let Some(kind) = written {
+            if kind.message_type() != var.kind.message_type() {
+                warn!(
+                    "Simulator rejecting write of {} to '{id}' of type {}",
+                    kind.message_type(),
+                    var.kind.message_type()
+                );
+                return Ok(Vec::new());
+            }
+            info!("Simulator variable '{id}' = {kind}");
+            var.kind = kind;
+        } else {
+            // Queries are empty
+            return reply_variable(var, 0);
+        }
+
+        if pkt.response() {
+            // Acknowledge by echoing the acknum
+            reply_variable(var, pkt.acknum())
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn announce_ids(&self) -> Result<Vec<Vec<u8>>, SimError> {
+        let mut replies = Vec::new();
+        for chunk in self.variables.chunks(IDS_PER_ANNOUNCEMENT) {
+            let mut payload = Vec::new();
+            for var in chunk.iter() {
+                payload.extend_from_slice(var.id.as_bytes());
+                payload.push(b'\0');
+            }
+            replies.push(reply(
+                MessageId::INTERNAL_AM_LIST,
+                true,
+                MessageType::Custom,
+                &payload,
+            )?);
+        }
+        let count = self.variables.len();
+        replies.push(match u8::try_from(count) {
+            Ok(count) => reply(MessageId::INTERNAL_AM_END, true, MessageType::U8, &[count])?,
+            Err(_) => reply(
+                MessageId::INTERNAL_AM_END,
+                true,
+                MessageType::U16,
+                &(count as u16).to_le_bytes(),
+            )?,
+        });
+        Ok(replies)
+    }
+}
+
+async fn serve_with<T, F>(io: T, mut handle: F) -> Result<(), SimError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(&Packet<Bytes>) -> Result<Vec<Vec<u8>>, SimError>,
+{
+    let mut dec_buf = Box::new([0_u8; offset::MAX_OFFSET_PACKET_SIZE]);
+    let mut codec = Framed::new(io, Codec::new(&mut dec_buf));
+
+    while let Some(pkt) = codec.next().await {
+        let pkt = match pkt {
+            Ok(pkt) => pkt,
+            Err(e) => {
+                warn!("Simulator dropping invalid data. {e}");
+                continue;
+            }
+        };
+        match handle(&pkt) {
+            Ok(replies) => {
+                for reply in replies.into_iter() {
+                    codec.send(Packet::new_unchecked(reply)).await?;
+                }
+            }
+            Err(e) => warn!("Simulator failed to handle packet {pkt}. {e}"),
+        }
+    }
+    info!("Simulator connection closed");
+    Ok(())
+}
+
+/// Encodes a variable reply, as offset packets if it doesn't fit in a single packet
+fn reply_variable(var: &Variable, acknum: u8) -> Result<Vec<Vec<u8>>, SimError> {
+    Ok(var.encode_requests(false, acknum)?)
+}
+
+fn reply(
+    id: MessageId<'_>,
+    internal: bool,
+    typ: MessageType,
+    payload: &[u8],
+) -> Result<Vec<u8>, PacketError> {
+    let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(id.len(), payload.len())];
+    let mut p = Packet::new_unchecked(&mut buf[..]);
+    p.set_data_length(payload.len() as _)?;
+    p.set_typ(typ);
+    p.set_internal(internal);
+    p.set_offset(false);
+    p.set_id_length(id.len() as _)?;
+    p.set_response(false);
+    p.set_acknum(0);
+    p.msg_id_mut()?.copy_from_slice(id.as_bytes());
+    p.payload_mut()?.copy_from_slice(payload);
+    p.set_checksum(p.compute_checksum()?)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientError, MAX_PACKET_SIZE};
+    use crate::link::LinkConfig;
+    use ordered_float::OrderedFloat;
+    use pretty_assertions::assert_eq;
+
+    const SCHEMA: &str = r#"
+        board_id = 0xBEEF
+        board_name = "sim"
+
+        [[variables]]
+        id = "led"
+        type = "U16"
+        value = 200
+
+        [[variables]]
+        id = "wave"
+        type = "F32Array"
+        value = [0.5, 1.5, -2.0]
+
+        [[variables]]
+        id = "reset"
+        type = "Callback"
+    "#;
+
+    fn id(s: &str) -> OwnedMessageId {
+        OwnedMessageId::from_utf8(s)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_against_simulator() {
+        let mut sim = Simulator::new(Schema::from_toml(SCHEMA).unwrap());
+        let (host, dev) = tokio::io::duplex(MAX_PACKET_SIZE * 4);
+        let server = tokio::spawn(async move {
+            sim.serve(dev).await.unwrap();
+            sim
+        });
+
+        let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+        let mut client = Client::new(host, &mut dec_buf, LinkConfig::default());
+
+        assert_eq!(client.board_id().await.unwrap(), BoardId::from(0xBEEF));
+        assert_eq!(client.board_name().await.unwrap().to_string(), "sim");
+        let ids = client.writable_ids().await.unwrap();
+        assert_eq!(
+            ids.as_slice(),
+            &[id("led"), id("wave"), id("reset"), id("name")]
+        );
+        let vars = client.tracked_variables().await.unwrap();
+        assert_eq!(vars.len(), 4);
+        assert_eq!(vars.as_slice()[0].kind, VariableKind::U16(200));
+        assert_eq!(vars.as_slice()[2].kind, VariableKind::Callback);
+
+        let wave = client.get(&id("wave")).await.unwrap();
+        assert_eq!(
+            wave.kind,
+            VariableKind::F32Array(vec![
+                OrderedFloat(0.5),
+                OrderedFloat(1.5),
+                OrderedFloat(-2.0)
+            ])
+        );
+
+        let led = Variable {
+            id: id("led"),
+            kind: VariableKind::U16(7),
+        };
+        client.set(&led).await.unwrap();
+        assert_eq!(client.get(&led.id).await.unwrap(), led);
+
+        let led = Variable {
+            id: id("led"),
+            kind: VariableKind::U16(8),
+        };
+        client.set_acked(&led).await.unwrap();
+        assert_eq!(client.get(&led.id).await.unwrap(), led);
+
+        // Writes of the wrong type are never acknowledged
+        let wrong = Variable {
+            id: id("led"),
+            kind: VariableKind::U8(1),
+        };
+        assert!(matches!(
+            client.set_acked(&wrong).await,
+            Err(ClientError::Unacknowledged { attempts: 3, .. })
+        ));
+
+        drop(client);
+        let sim = server.await.unwrap();
+        assert_eq!(sim.variables()[0], led);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::device::DeviceUri;
+use crate::opts::SimulateOpts;
+use electricui_cli::sim::{Schema, Simulator};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+pub async fn simulate(opts: SimulateOpts) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let schema = Schema::load(&opts.schema)?;
+    let mut sim = Simulator::new(schema);
+
+    match opts.listen {
+        Some(DeviceUri::Tcp(addr)) => {
+            let listener = TcpListener::bind(&addr).await?;
+            println!("Listening on tcp://{}", listener.local_addr()?);
+            let sim = Arc::new(Mutex::new(sim));
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => spawn_connection(&sim, stream, peer),
+                    Err(e) => warn!("Failed to accept a connection. {e}"),
+                }
+            }
+        }
+        #[cfg(unix)]
+        Some(DeviceUri::Unix(path)) => {
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            println!("Listening on unix://{}", path);
+            let sim = Arc::new(Mutex::new(sim));
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => spawn_connection(&sim, stream, &path),
+                    Err(e) => warn!("Failed to accept a connection. {e}"),
+                }
+            }
+        }
+        Some(uri) => Err(format!("Cannot listen on '{uri}', use a tcp:// or unix:// URI").into()),
+        None => serve_pty(&mut sim).await,
+    }
+}
+
+/// Serves a connection in its own task, so a misbehaving host only ends its own connection
+fn spawn_connection<T, P>(sim: &Arc<Mutex<Simulator>>, stream: T, peer: P)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    P: Display,
+{
+    info!("Accepted connection from {peer}");
+    let sim = Arc::clone(sim);
+    let peer = peer.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = Simulator::serve_shared(&sim, stream).await {
+            warn!("Connection from {peer} failed. {e}");
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn serve_pty(sim: &mut Simulator) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use tokio_serial::{SerialPort, SerialStream};
+
+    let (mut master, mut slave) = SerialStream::pair()?;
+    // Hold the slave end open so the master never sees a hangup between connections
+    slave.set_exclusive(false)?;
+    let name = slave.name().ok_or("PTY has no name")?;
+    println!("Serving on {}", name);
+    loop {
+        sim.serve(&mut master).await?;
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_pty(_sim: &mut Simulator) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("PTYs are not supported on this platform, use --listen".into())
+}
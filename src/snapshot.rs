@@ -0,0 +1,368 @@
+use crate::device;
+use crate::opts::{SnapshotCommand, SnapshotRestoreOpts, SnapshotSaveOpts};
+use crate::output::{Format, Output, Render};
+use electricui_cli::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use thiserror::Error;
+use tracing::warn;
+
+pub async fn snapshot(
+    cmd: SnapshotCommand,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match cmd {
+        SnapshotCommand::Save(opts) => save(opts, format, record).await,
+        SnapshotCommand::Restore(opts) => restore(opts, format, record).await,
+    }
+}
+
+/// The values of a board's writable variables at one point in time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub board_id: BoardId,
+    pub board_name: BoardName,
+    pub timestamp: String,
+    pub variables: Vec<Variable>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(fs::write(path, serde_json::to_string_pretty(self)?)?)
+    }
+}
+
+async fn save(
+    opts: SnapshotSaveOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    let snapshot = Snapshot {
+        board_id: client.board_id().await?,
+        board_name: client.board_name().await?,
+        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        // Callbacks hold no value to restore
+        variables: client
+            .tracked_variables()
+            .await?
+            .as_slice()
+            .iter()
+            .filter(|var| !var.kind.is_callback())
+            .cloned()
+            .collect(),
+    };
+    snapshot.save(&opts.snapshot)?;
+
+    Output::new(format).emit(&SnapshotReport {
+        snapshot: opts.snapshot.display().to_string(),
+        variables: snapshot
+            .variables
+            .into_iter()
+            .map(|var| SnapshotEntry {
+                id: var.id,
+                status: Status::Saved,
+                value: Some(var.kind),
+                expected: None,
+                actual: None,
+            })
+            .collect(),
+    })?;
+    Ok(())
+}
+
+async fn restore(
+    opts: SnapshotRestoreOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let snapshot = Snapshot::load(&opts.snapshot)?;
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    // Restores usually follow a reflash, so the types come from the board rather than the cache
+    let schema = client.schema(None).await?;
+    if schema.board_id != snapshot.board_id || schema.board_name != snapshot.board_name {
+        warn!(
+            "Restoring a snapshot of '{}' (0x{:04X}) to '{}' (0x{:04X})",
+            snapshot.board_name, snapshot.board_id, schema.board_name, schema.board_id
+        );
+    }
+
+    let mut report = SnapshotReport {
+        snapshot: opts.snapshot.display().to_string(),
+        variables: Vec::new(),
+    };
+    for var in snapshot.variables.into_iter() {
+        let mut entry = restore_entry(&schema, &var);
+        if entry.status == Status::Written {
+            let res = if opts.ack {
+                client.set_acked(&var).await
+            } else {
+                client.set(&var).await
+            };
+            match res {
+                Ok(()) => {
+                    // Without an acknowledgement nothing confirms the board took the value
+                    if !opts.ack {
+                        entry.status = Status::Sent;
+                    }
+                    entry.value = Some(var.kind);
+                }
+                Err(e @ ClientError::Unacknowledged { .. }) => {
+                    warn!("{e}");
+                    entry.status = Status::Unacknowledged;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        report.variables.push(entry);
+    }
+
+    Output::new(format).emit(&report)?;
+    let failed = report
+        .variables
+        .iter()
+        .filter(|v| {
+            !matches!(
+                v.status,
+                Status::Written | Status::Sent | Status::SkippedCallback
+            )
+        })
+        .count();
+    if failed != 0 {
+        return Err(SnapshotRestoreError(failed).into());
+    }
+    Ok(())
+}
+
+/// Decides whether a saved variable can be restored to a board with `schema`,
+/// returning an entry with the [`Status::Written`] status if it should be written
+fn restore_entry(schema: &BoardSchema, var: &Variable) -> SnapshotEntry {
+    let saved = VariableSchema::of(var);
+    let mut entry = SnapshotEntry {
+        id: var.id.clone(),
+        status: Status::Written,
+        value: None,
+        expected: None,
+        actual: None,
+    };
+    match schema.get(&var.id) {
+        _ if var.kind.is_callback() => entry.status = Status::SkippedCallback,
+        None => entry.status = Status::Missing,
+        Some(typ) if !typ.accepts(&saved) => {
+            entry.status = Status::TypeMismatch;
+            entry.expected = Some(saved.to_string());
+            entry.actual = Some(typ.to_string());
+        }
+        Some(_) => (),
+    }
+    entry
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Saved,
+    /// The board acknowledged the write
+    Written,
+    /// Written without an acknowledgement, so unconfirmed
+    Sent,
+    SkippedCallback,
+    /// The board has no variable with the saved ID
+    Missing,
+    /// The board's variable has a different type, shape or length than the saved value
+    TypeMismatch,
+    Unacknowledged,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Saved => f.write_str("saved"),
+            Status::Written => f.write_str("written"),
+            Status::Sent => f.write_str("sent"),
+            Status::SkippedCallback => f.write_str("skipped callback"),
+            Status::Missing => f.write_str("missing"),
+            Status::TypeMismatch => f.write_str("type mismatch"),
+            Status::Unacknowledged => f.write_str("unacknowledged"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotEntry {
+    pub id: OwnedMessageId,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<VariableKind>,
+    /// Type of the saved value, for type mismatches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    /// Type of the board's variable, for type mismatches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotReport {
+    pub snapshot: String,
+    pub variables: Vec<SnapshotEntry>,
+}
+
+impl Render for SnapshotReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "Snapshot '{}':", self.snapshot)?;
+        for v in self.variables.iter() {
+            write!(w, "  {} {}", v.id, v.status)?;
+            if let Some(value) = &v.value {
+                write!(w, ", {value}")?;
+            }
+            if let (Some(expected), Some(actual)) = (&v.expected, &v.actual) {
+                write!(w, ", saved {expected} but the board has {actual}")?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["id", "status", "value", "expected", "actual"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.variables
+            .iter()
+            .map(|v| {
+                vec![
+                    v.id.to_string(),
+                    v.status.to_string(),
+                    v.value.as_ref().map(|k| k.to_string()).unwrap_or_default(),
+                    v.expected.clone().unwrap_or_default(),
+                    v.actual.clone().unwrap_or_default(),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{0} variable(s) of the snapshot were not restored")]
+pub struct SnapshotRestoreError(pub usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn var(id: &str, kind: VariableKind) -> Variable {
+        Variable {
+            id: OwnedMessageId::from_utf8(id),
+            kind,
+        }
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let snapshot = Snapshot {
+            board_id: 0xBEEF.into(),
+            board_name: b"demo".to_vec().into(),
+            timestamp: "2024-01-01T00:00:00.000Z".to_owned(),
+            variables: vec![
+                var("led", VariableKind::U16(200)),
+                var("gain", VariableKind::F32(f32::NAN.into())),
+                var(
+                    "wave",
+                    VariableKind::F64Array(vec![
+                        1.5.into(),
+                        f64::INFINITY.into(),
+                        f64::NEG_INFINITY.into(),
+                    ]),
+                ),
+            ],
+        };
+        let path = std::env::temp_dir().join(format!(
+            "electricui-snapshot-test-{}.json",
+            std::process::id()
+        ));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        let _ = fs::remove_file(&path);
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.board_id, snapshot.board_id);
+        assert_eq!(loaded.board_name, snapshot.board_name);
+        assert_eq!(loaded.timestamp, snapshot.timestamp);
+        assert_eq!(loaded.variables, snapshot.variables);
+    }
+
+    #[test]
+    fn restore_entries() {
+        let schema = BoardSchema {
+            board_id: 0xBEEF.into(),
+            board_name: b"demo".to_vec().into(),
+            ids_count: 3,
+            variables: vec![
+                VariableSchema::of(&var("led", VariableKind::U16(0))),
+                VariableSchema::of(&var("big", VariableKind::U8Array(vec![0; 3]))),
+                VariableSchema::of(&var("go", VariableKind::Callback)),
+            ],
+        };
+        let entry = |v: Variable| {
+            let e = restore_entry(&schema, &v);
+            (e.status, e.value, e.expected, e.actual)
+        };
+
+        assert_eq!(
+            entry(var("led", VariableKind::U16(7))),
+            (Status::Written, None, None, None)
+        );
+        assert_eq!(
+            entry(var("go", VariableKind::Callback)),
+            (Status::SkippedCallback, None, None, None)
+        );
+        assert_eq!(
+            entry(var("gone", VariableKind::U8(1))),
+            (Status::Missing, None, None, None)
+        );
+        assert_eq!(
+            entry(var("led", VariableKind::I16(7))),
+            (
+                Status::TypeMismatch,
+                None,
+                Some("I16".to_owned()),
+                Some("U16".to_owned())
+            )
+        );
+        assert_eq!(
+            entry(var("big", VariableKind::U8Array(vec![1, 2]))),
+            (
+                Status::TypeMismatch,
+                None,
+                Some("U8[2]".to_owned()),
+                Some("U8[3]".to_owned())
+            )
+        );
+    }
+}
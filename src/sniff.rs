@@ -0,0 +1,44 @@
+use crate::device;
+use crate::opts::SniffOpts;
+use crate::output::{Format, Output};
+use bytes::BytesMut;
+use electricui_cli::capture::{Direction, Recorder};
+use electricui_cli::codec::FrameDecoder;
+use electricui_cli::prelude::*;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+pub async fn sniff(
+    opts: SniffOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let mut recorder = match record {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+    // Only ever read from the device, nothing is sent
+    let mut dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut dec = FrameDecoder::new(&mut dec_buf, opts.direction, true);
+    let mut buf = BytesMut::with_capacity(MAX_PACKET_SIZE);
+
+    loop {
+        if dev.read_buf(&mut buf).await? == 0 {
+            info!("Device closed");
+            return Ok(());
+        }
+        for (frame, entry) in dec.decode(&mut buf) {
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.write(
+                    opts.direction.unwrap_or(Direction::Rx),
+                    &frame,
+                    entry.error.as_deref(),
+                )?;
+            }
+            output.emit(&entry)?;
+        }
+    }
+}
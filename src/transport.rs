@@ -0,0 +1,8 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A byte stream to a device, e.g. a serial port, socket or in-memory test double
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type BoxedTransport = Box<dyn Transport>;
@@ -0,0 +1,359 @@
+use crate::device;
+use crate::opts::TuiOpts;
+use crate::output;
+use bytes::Bytes;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use electricui_cli::prelude::*;
+use electricui_embedded::prelude::*;
+use futures::StreamExt;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// Number of samples kept for each numeric variable's sparkline
+const HISTORY_LEN: usize = 256;
+
+/// Width of the sparkline column in the variables table
+const SPARK_WIDTH: usize = 24;
+
+const SPARK_BARS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn tui(
+    opts: TuiOpts,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = device::link_config(&opts.device, record)?;
+    // The dashboard runs its own monitor so a lost link is displayed rather than fatal
+    let monitor = config.heartbeat.take().unwrap_or_else(|| {
+        HeartbeatMonitor::new(DEFAULT_HEARTBEAT_INTERVAL, opts.device.heartbeat_misses)
+    });
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(dev, &mut dec_buf, config);
+
+    let board_id = client.board_id().await?;
+    let board_name = client.board_name().await?;
+    let writable = client
+        .writable_ids()
+        .await?
+        .as_slice()
+        .iter()
+        .cloned()
+        .collect();
+    let mut dashboard = Dashboard {
+        board_id,
+        board_name,
+        writable,
+        rows: Vec::new(),
+        table: TableState::default().with_selected(Some(0)),
+        editing: None,
+        status: None,
+        monitor,
+        link: None,
+        reassembler: Reassembler::default(),
+    };
+    for var in client.tracked_variables().await?.as_slice().iter() {
+        dashboard.update(var.clone());
+    }
+
+    let mut terminal = ratatui::init();
+    let res = run(
+        &mut terminal,
+        &mut client,
+        &mut dashboard,
+        opts.interval.into(),
+    )
+    .await;
+    ratatui::restore();
+    res
+}
+
+async fn run<T: AsyncRead + AsyncWrite + Unpin, const N: usize>(
+    terminal: &mut DefaultTerminal,
+    client: &mut Client<'_, T, N>,
+    dashboard: &mut Dashboard,
+    interval: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut events = EventStream::new();
+    let mut poll = time::interval(interval);
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut heartbeat = time::interval(dashboard.monitor.interval());
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        terminal.draw(|f| dashboard.draw(f))?;
+        tokio::select! {
+            _ = poll.tick() => client.send_tracked_variables_request().await?,
+            _ = heartbeat.tick() => {
+                let (hb, event) = dashboard.monitor.next_request(Instant::now());
+                dashboard.link = event.or(dashboard.link);
+                client.send_heartbeat(hb).await?;
+            }
+            pkt = client.recv() => dashboard.on_packet(&pkt?),
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    match dashboard.on_key(key) {
+                        Some(Action::Quit) => return Ok(()),
+                        Some(Action::Write(var)) => {
+                            dashboard.status = Some(match client.set(&var).await {
+                                Ok(()) => format!("Wrote {var}"),
+                                Err(e) => format!("Failed to write '{}'. {e}", var.id),
+                            });
+                        }
+                        None => (),
+                    }
+                }
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+enum Action {
+    Quit,
+    Write(Variable),
+}
+
+struct VariableRow {
+    var: Variable,
+    writable: bool,
+    history: VecDeque<f64>,
+}
+
+struct Dashboard {
+    board_id: BoardId,
+    board_name: BoardName,
+    writable: HashSet<OwnedMessageId>,
+    rows: Vec<VariableRow>,
+    table: TableState,
+    /// Text being entered for the selected row
+    editing: Option<String>,
+    status: Option<String>,
+    monitor: HeartbeatMonitor,
+    link: Option<LinkEvent>,
+    reassembler: Reassembler,
+}
+
+impl Dashboard {
+    fn update(&mut self, var: Variable) {
+        let row = match self.rows.iter_mut().position(|r| r.var.id == var.id) {
+            Some(idx) => &mut self.rows[idx],
+            None => {
+                self.rows.push(VariableRow {
+                    writable: self.writable.contains(&var.id) && !var.kind.is_callback(),
+                    var: var.clone(),
+                    history: VecDeque::with_capacity(HISTORY_LEN),
+                });
+                self.rows.last_mut().unwrap()
+            }
+        };
+        if let Some(v) = var.kind.as_f64() {
+            if row.history.len() == HISTORY_LEN {
+                row.history.pop_front();
+            }
+            row.history.push_back(v);
+        }
+        row.var = var;
+    }
+
+    fn on_packet(&mut self, pkt: &Packet<Bytes>) {
+        if pkt.internal() {
+            let is_heartbeat = pkt
+                .msg_id()
+                .is_ok_and(|id| id == MessageId::INTERNAL_HEARTBEAT);
+            if is_heartbeat {
+                if let Ok(hb) = Heartbeat::decode_response(pkt) {
+                    let event = self.monitor.on_response(hb, Instant::now());
+                    self.link = event.or(self.link);
+                }
+            }
+            return;
+        }
+        match self.reassembler.push(pkt) {
+            Ok(Some(var)) => self.update(var),
+            Ok(None) => (),
+            Err(e) => self.status = Some(format!("Failed to decode {pkt}. {e}")),
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+        let selected = self.table.selected().and_then(|idx| self.rows.get(idx));
+
+        if let Some(text) = self.editing.as_mut() {
+            match key.code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => self.editing = None,
+                KeyCode::Enter => {
+                    let text = self.editing.take().unwrap_or_default();
+                    let row = selected?;
+                    let kind = &row.var.kind;
+                    match VariableKind::parse(kind.message_type(), Some(kind.is_array()), &text) {
+                        Ok(kind) => {
+                            return Some(Action::Write(Variable {
+                                id: row.var.id.clone(),
+                                kind,
+                            }))
+                        }
+                        Err(e) => self.status = Some(e.to_string()),
+                    }
+                }
+                _ => (),
+            }
+            return None;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+            KeyCode::Enter | KeyCode::Char('e') => match selected {
+                Some(row) if row.writable => {
+                    self.editing = Some(output::kind_value_string(&row.var.kind));
+                    self.status = None;
+                }
+                Some(row) => self.status = Some(format!("'{}' is not writable", row.var.id)),
+                None => (),
+            },
+            _ => (),
+        }
+        None
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        let [header, table, detail, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(5),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(f.area());
+
+        let link = match self.link {
+            Some(LinkEvent::Up) => "up".green(),
+            Some(LinkEvent::Down) => "down".red().bold(),
+            None => "unknown".yellow(),
+        };
+        let stats = self.monitor.stats();
+        let rtt = stats
+            .last_rtt
+            .map_or_else(|| "-".to_owned(), |rtt| format!("{rtt:?}"));
+        f.render_widget(
+            Paragraph::new(vec![
+                Line::from(format!(
+                    "Board '{}' (0x{:04X}), {} variables",
+                    self.board_name,
+                    self.board_id,
+                    self.rows.len()
+                )),
+                Line::from(vec![
+                    "Link ".into(),
+                    link,
+                    format!(
+                        ", heartbeats sent {} acked {} missed {}, rtt {rtt}",
+                        stats.sent, stats.acked, stats.missed
+                    )
+                    .into(),
+                ]),
+            ])
+            .block(Block::bordered().title(" electricui ")),
+            header,
+        );
+
+        let selected = self.table.selected();
+        let rows = self.rows.iter().enumerate().map(|(idx, row)| {
+            let value = match (&self.editing, selected == Some(idx)) {
+                (Some(text), true) => format!("{text}_"),
+                _ => output::kind_value_string(&row.var.kind),
+            };
+            let id = if row.writable {
+                format!("{}*", row.var.id)
+            } else {
+                row.var.id.to_string()
+            };
+            Row::new(vec![
+                id,
+                row.var.kind.message_type().to_string(),
+                value,
+                spark_text(&row.history),
+            ])
+        });
+        let widths = [
+            Constraint::Percentage(20),
+            Constraint::Length(10),
+            Constraint::Fill(1),
+            Constraint::Length(SPARK_WIDTH as u16),
+        ];
+        let highlight = match self.editing {
+            Some(_) => Style::new().bg(Color::Yellow).fg(Color::Black),
+            None => Style::new().reversed(),
+        };
+        f.render_stateful_widget(
+            Table::new(rows, widths)
+                .header(Row::new(vec!["ID", "Type", "Value", "History"]).bold())
+                .highlight_style(highlight)
+                .block(Block::bordered().title(" Variables, * writable ")),
+            table,
+            &mut self.table,
+        );
+
+        let (title, data) = match selected.and_then(|idx| self.rows.get(idx)) {
+            Some(row) => (format!(" '{}' ", row.var.id), scaled(&row.history)),
+            None => (" ".to_owned(), Vec::new()),
+        };
+        f.render_widget(
+            Sparkline::default()
+                .data(&data[data.len().saturating_sub(detail.width as usize)..])
+                .style(Style::new().cyan())
+                .block(Block::bordered().title(title)),
+            detail,
+        );
+
+        let help = "q quit, up/down select, enter edit, esc cancel";
+        f.render_widget(
+            Paragraph::new(self.status.clone().unwrap_or_else(|| help.to_owned())),
+            footer,
+        );
+    }
+}
+
+/// History scaled onto 0..=100, sparklines need unsigned data
+fn scaled(history: &VecDeque<f64>) -> Vec<u64> {
+    let min = history.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = history.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = max - min;
+    history
+        .iter()
+        .map(|v| {
+            if range > 0.0 {
+                (1.0 + (v - min) / range * 99.0) as u64
+            } else {
+                50
+            }
+        })
+        .collect()
+}
+
+fn spark_text(history: &VecDeque<f64>) -> String {
+    let data = scaled(history);
+    data[data.len().saturating_sub(SPARK_WIDTH)..]
+        .iter()
+        .map(|v| SPARK_BARS[(*v as usize * (SPARK_BARS.len() - 1)) / 100])
+        .collect()
+}
@@ -1,9 +1,17 @@
-use crate::error::{PacketError, PacketProtocolError};
+use crate::error::{
+    IdsCountMismatchError, InvalidMessageIdError, PacketError, PacketProtocolError,
+    ParseVariableError,
+};
+use crate::offset;
 use byteorder::{ByteOrder, LittleEndian};
 use derive_more::{Display, From, Into, IsVariant, Unwrap, UpperHex};
-use electricui_embedded::prelude::*;
+use electricui_embedded::{prelude::*, wire::packet};
 use ordered_float::OrderedFloat;
-use std::{fmt, str};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str, str::FromStr};
+
+/// Largest acknum that fits in the 3 bit header field, zero means no acknowledgement
+pub const MAX_ACKNUM: u8 = 7;
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Into)]
 pub struct OwnedMessageId(Vec<u8>);
@@ -49,7 +57,42 @@ impl fmt::Display for OwnedMessageId {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, IsVariant, Unwrap, Display)]
+impl Serialize for OwnedMessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for OwnedMessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for OwnedMessageId {
+    type Err = InvalidMessageIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s.as_bytes()).ok_or_else(|| InvalidMessageIdError(s.to_owned()))
+    }
+}
+
+#[derive(
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    IsVariant,
+    Unwrap,
+    Display,
+    Serialize,
+    Deserialize,
+)]
+#[serde(tag = "type", content = "value")]
 pub enum VariableKind {
     #[display(fmt = "Callback")]
     Callback,
@@ -89,12 +132,16 @@ pub enum VariableKind {
     U32(u32),
     #[display(fmt = "U32Array({:?})", _0)]
     U32Array(Vec<u32>),
+    #[serde(with = "float_serde::scalar")]
     #[display(fmt = "F32({})", _0)]
     F32(OrderedFloat<f32>),
+    #[serde(with = "float_serde::array")]
     #[display(fmt = "F32Array({:?})", _0)]
     F32Array(Vec<OrderedFloat<f32>>),
+    #[serde(with = "float_serde::scalar")]
     #[display(fmt = "F64({})", _0)]
     F64(OrderedFloat<f64>),
+    #[serde(with = "float_serde::array")]
     #[display(fmt = "F64Array({:?})", _0)]
     F64Array(Vec<OrderedFloat<f64>>),
 }
@@ -104,19 +151,16 @@ impl VariableKind {
         // TODO - protocol sanity checks, size checks, etc
         let num_elements = typ.array_wire_length_hint(data.len());
         let expected_size = typ.array_wire_size_hint(num_elements);
-        if typ.wire_size_hint() != 0 && (expected_size != data.len()) {
+        if typ.wire_size_hint() != 0 && (expected_size != data.len() || num_elements == 0) {
             return Err(PacketProtocolError::ProtocolViolation);
         }
-        // TODO check expected_size == data.len() on types that need it
-        // err if num_elements == 0 on types that need it
         let is_array = num_elements > 1;
         Ok(match typ {
             MessageType::Callback => VariableKind::Callback,
             MessageType::Custom => VariableKind::Custom(data.to_vec()),
             MessageType::Unknown(t) => VariableKind::Unknown(t, data.to_vec()),
-            // TODO - protocol still doesn't support offset packets, just throw them into unknown
-            // for now
-            MessageType::OffsetMetadata => VariableKind::Unknown(typ.into(), Default::default()),
+            // Offset metadata describes a transfer rather than a value, see the offset module
+            MessageType::OffsetMetadata => return Err(PacketProtocolError::ProtocolViolation),
             MessageType::Byte => {
                 if is_array {
                     VariableKind::ByteArray(data.to_vec())
@@ -203,17 +247,377 @@ impl VariableKind {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Display)]
+impl VariableKind {
+    pub fn message_type(&self) -> MessageType {
+        use VariableKind::*;
+        match self {
+            Callback => MessageType::Callback,
+            Custom(_) => MessageType::Custom,
+            Unknown(t, _) => MessageType::from(*t),
+            Byte(_) | ByteArray(_) => MessageType::Byte,
+            Char(_) | CharArray(_) => MessageType::Char,
+            I8(_) | I8Array(_) => MessageType::I8,
+            U8(_) | U8Array(_) => MessageType::U8,
+            I16(_) | I16Array(_) => MessageType::I16,
+            U16(_) | U16Array(_) => MessageType::U16,
+            I32(_) | I32Array(_) => MessageType::I32,
+            U32(_) | U32Array(_) => MessageType::U32,
+            F32(_) | F32Array(_) => MessageType::F32,
+            F64(_) | F64Array(_) => MessageType::F64,
+        }
+    }
+
+    pub fn is_array(&self) -> bool {
+        use VariableKind::*;
+        matches!(
+            self,
+            ByteArray(_)
+                | CharArray(_)
+                | I8Array(_)
+                | U8Array(_)
+                | I16Array(_)
+                | U16Array(_)
+                | I32Array(_)
+                | U32Array(_)
+                | F32Array(_)
+                | F64Array(_)
+        )
+    }
+
+    /// Number of elements of arrays, bytes for character arrays, `None` for scalars
+    pub fn array_len(&self) -> Option<usize> {
+        use VariableKind::*;
+        Some(match self {
+            ByteArray(v) | U8Array(v) => v.len(),
+            CharArray(v) => v.len(),
+            I8Array(v) => v.len(),
+            I16Array(v) => v.len(),
+            U16Array(v) => v.len(),
+            I32Array(v) => v.len(),
+            U32Array(v) => v.len(),
+            F32Array(v) => v.len(),
+            F64Array(v) => v.len(),
+            _ => return None,
+        })
+    }
+
+    /// The value of numeric scalars, `None` for arrays, characters and callbacks
+    pub fn as_f64(&self) -> Option<f64> {
+        use VariableKind::*;
+        Some(match self {
+            Byte(v) | U8(v) => f64::from(*v),
+            I8(v) => f64::from(*v),
+            I16(v) => f64::from(*v),
+            U16(v) => f64::from(*v),
+            I32(v) => f64::from(*v),
+            U32(v) => f64::from(*v),
+            F32(v) => f64::from(v.0),
+            F64(v) => v.0,
+            _ => return None,
+        })
+    }
+
+    /// The elements of numeric arrays, `None` for scalars, character arrays and callbacks
+    pub fn as_f64_array(&self) -> Option<Vec<f64>> {
+        use VariableKind::*;
+        Some(match self {
+            ByteArray(v) | U8Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            I8Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            I16Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            U16Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            I32Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            U32Array(v) => v.iter().map(|v| f64::from(*v)).collect(),
+            F32Array(v) => v.iter().map(|v| f64::from(v.0)).collect(),
+            F64Array(v) => v.iter().map(|v| v.0).collect(),
+            _ => return None,
+        })
+    }
+
+    /// Encodes the variable into its wire type and little-endian payload bytes,
+    /// the inverse of [`VariableKind::from_wire`].
+    ///
+    /// Single element arrays share their wire representation with the scalar variant.
+    pub fn to_wire(&self) -> Result<(MessageType, Vec<u8>), PacketProtocolError> {
+        let (typ, data) = self.to_wire_unbounded()?;
+        if data.len() > Packet::<&[u8]>::MAX_PAYLOAD_SIZE {
+            return Err(PacketError(packet::Error::InvalidDataLength).into());
+        }
+        Ok((typ, data))
+    }
+
+    /// Like [`VariableKind::to_wire`], without the single packet payload limit
+    /// since the data may be sent as offset packets
+    pub fn to_wire_unbounded(&self) -> Result<(MessageType, Vec<u8>), PacketProtocolError> {
+        use VariableKind::*;
+        let typ = self.message_type();
+        let data = match self {
+            Callback => Vec::new(),
+            Custom(v) => v.clone(),
+            Unknown(_, v) => match typ {
+                MessageType::Unknown(_) => v.clone(),
+                _ => return Err(PacketProtocolError::ProtocolViolation),
+            },
+            Byte(v) | U8(v) => vec![*v],
+            Char(c) => vec![u8::try_from(*c).map_err(|_| PacketProtocolError::ProtocolViolation)?],
+            CharArray(s) => s.as_bytes().to_vec(),
+            I8(v) => vec![*v as u8],
+            I16(v) => v.to_le_bytes().to_vec(),
+            U16(v) => v.to_le_bytes().to_vec(),
+            I32(v) => v.to_le_bytes().to_vec(),
+            U32(v) => v.to_le_bytes().to_vec(),
+            F32(v) => v.0.to_le_bytes().to_vec(),
+            F64(v) => v.0.to_le_bytes().to_vec(),
+            ByteArray(v) | U8Array(v) => v.clone(),
+            I8Array(v) => v.iter().map(|b| *b as u8).collect(),
+            I16Array(v) => v.iter().flat_map(|e| e.to_le_bytes()).collect(),
+            U16Array(v) => v.iter().flat_map(|e| e.to_le_bytes()).collect(),
+            I32Array(v) => v.iter().flat_map(|e| e.to_le_bytes()).collect(),
+            U32Array(v) => v.iter().flat_map(|e| e.to_le_bytes()).collect(),
+            F32Array(v) => v.iter().flat_map(|e| e.0.to_le_bytes()).collect(),
+            F64Array(v) => v.iter().flat_map(|e| e.0.to_le_bytes()).collect(),
+        };
+        if typ.wire_size_hint() != 0 && data.is_empty() {
+            // Empty arrays have no wire representation
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        Ok((typ, data))
+    }
+
+    /// Parses the textual representation of a value of type `typ`.
+    ///
+    /// Arrays are comma separated, optionally enclosed in brackets.
+    /// When `is_array` is `None` it is inferred from the input.
+    pub fn parse(
+        typ: MessageType,
+        is_array: Option<bool>,
+        s: &str,
+    ) -> Result<Self, ParseVariableError> {
+        let s = s.trim();
+        let invalid = || ParseVariableError::InvalidValue(typ, s.to_owned());
+        if typ == MessageType::Char {
+            let s = strip_delimiters(s, '"', '"');
+            let is_array = is_array.unwrap_or(s.chars().count() != 1);
+            return if is_array {
+                Ok(VariableKind::CharArray(s.to_owned()))
+            } else {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if u8::try_from(c).is_ok() => Ok(VariableKind::Char(c)),
+                    _ => Err(invalid()),
+                }
+            };
+        }
+
+        let is_array = is_array.unwrap_or_else(|| s.contains(',') || s.starts_with('['));
+        let elements: Vec<&str> = if is_array {
+            strip_delimiters(s, '[', ']')
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .collect()
+        } else {
+            vec![s]
+        };
+
+        macro_rules! ints {
+            ($scalar:ident, $array:ident) => {{
+                let v = elements
+                    .iter()
+                    .map(|e| parse_int(e).ok_or_else(invalid))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if is_array {
+                    VariableKind::$array(v)
+                } else {
+                    VariableKind::$scalar(v[0])
+                }
+            }};
+        }
+        macro_rules! floats {
+            ($scalar:ident, $array:ident) => {{
+                let v = elements
+                    .iter()
+                    .map(|e| e.parse().map(OrderedFloat).map_err(|_| invalid()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if is_array {
+                    VariableKind::$array(v)
+                } else {
+                    VariableKind::$scalar(v[0])
+                }
+            }};
+        }
+
+        Ok(match typ {
+            MessageType::Byte => ints!(Byte, ByteArray),
+            MessageType::I8 => ints!(I8, I8Array),
+            MessageType::U8 => ints!(U8, U8Array),
+            MessageType::I16 => ints!(I16, I16Array),
+            MessageType::U16 => ints!(U16, U16Array),
+            MessageType::I32 => ints!(I32, I32Array),
+            MessageType::U32 => ints!(U32, U32Array),
+            MessageType::F32 => floats!(F32, F32Array),
+            MessageType::F64 => floats!(F64, F64Array),
+            _ => return Err(ParseVariableError::UnsupportedType(typ)),
+        })
+    }
+}
+
+fn strip_delimiters(s: &str, start: char, end: char) -> &str {
+    s.strip_prefix(start)
+        .and_then(|s| s.strip_suffix(end))
+        .unwrap_or(s)
+}
+
+fn parse_int<T: FromStr + TryFrom<i64>>(s: &str) -> Option<T> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16)
+            .ok()
+            .and_then(|v| T::try_from(v).ok()),
+        None => s.parse().ok(),
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Display, Serialize, Deserialize)]
 #[display(fmt = "Id({}), Kind({})", id, kind)]
 pub struct Variable {
     pub id: OwnedMessageId,
+    #[serde(flatten)]
     pub kind: VariableKind,
 }
 
+impl Variable {
+    /// Decodes a variable from a single packet,
+    /// see [`Reassembler`](crate::offset::Reassembler) for offset packets
+    pub fn decode_response<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<Self, PacketProtocolError> {
+        // TODO - sanity check protocol
+        if p.offset() {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        let id = p.msg_id()?;
+        let typ = p.typ();
+        let data = p.payload()?;
+        Ok(Variable {
+            id: OwnedMessageId::from_wire(&id),
+            kind: VariableKind::from_wire(typ, data)?,
+        })
+    }
+
+    pub fn encode_request<T: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        response: bool,
+        p: &mut Packet<T>,
+    ) -> Result<(), PacketProtocolError> {
+        self.encode(false, response, 0, p)
+    }
+
+    /// Encodes a write the board acknowledges by echoing `acknum`,
+    /// which must be in `1..=7` since zero means no acknowledgement
+    pub fn encode_acked_request<T: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        acknum: u8,
+        p: &mut Packet<T>,
+    ) -> Result<(), PacketProtocolError> {
+        if acknum == 0 || acknum > MAX_ACKNUM {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        self.encode(false, true, acknum, p)
+    }
+
+    /// Encodes a request with every header flag chosen by the caller,
+    /// e.g. to craft internal messages
+    pub fn encode_raw_request<T: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        internal: bool,
+        response: bool,
+        acknum: u8,
+        p: &mut Packet<T>,
+    ) -> Result<(), PacketProtocolError> {
+        if acknum > MAX_ACKNUM {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
+        self.encode(internal, response, acknum, p)
+    }
+
+    /// Encodes a write as a single packet when it fits, otherwise as offset packets
+    pub fn encode_requests(
+        &self,
+        response: bool,
+        acknum: u8,
+    ) -> Result<Vec<Vec<u8>>, PacketProtocolError> {
+        let (_, data) = self.kind.to_wire_unbounded()?;
+        if data.len() > offset::MAX_CHUNK_SIZE {
+            return offset::encode_chunks(self, response, acknum);
+        }
+        let mut buf = vec![0_u8; Packet::<&[u8]>::buffer_len(self.id.len(), data.len())];
+        self.encode(
+            false,
+            response,
+            acknum,
+            &mut Packet::new_unchecked(&mut buf[..]),
+        )?;
+        Ok(vec![buf])
+    }
+
+    fn encode<T: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        internal: bool,
+        response: bool,
+        acknum: u8,
+        p: &mut Packet<T>,
+    ) -> Result<(), PacketProtocolError> {
+        let (typ, payload) = self.kind.to_wire()?;
+        p.set_data_length(payload.len() as _)?;
+        p.set_typ(typ);
+        p.set_internal(internal);
+        p.set_offset(false);
+        p.set_id_length(self.id.len() as _)?;
+        p.set_response(response);
+        p.set_acknum(acknum);
+        p.msg_id_mut()?.copy_from_slice(self.id.as_bytes());
+        p.payload_mut()?.copy_from_slice(&payload);
+        p.set_checksum(p.compute_checksum()?)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct VariableQuery;
+
+impl VariableQuery {
+    pub fn encode_request<T: AsRef<[u8]> + AsMut<[u8]>>(
+        id: &OwnedMessageId,
+        p: &mut Packet<T>,
+    ) -> Result<(), PacketError> {
+        p.set_data_length(0)?;
+        p.set_typ(MessageType::Callback);
+        p.set_internal(false);
+        p.set_offset(false);
+        p.set_id_length(id.len() as _)?;
+        p.set_response(true);
+        p.set_acknum(0);
+        p.msg_id_mut()?.copy_from_slice(id.as_bytes());
+        p.set_checksum(p.compute_checksum()?)?;
+        Ok(())
+    }
+}
+
 // TODO zero is invalid, no From
 #[derive(
-    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Display, UpperHex, From, Into,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    UpperHex,
+    From,
+    Into,
+    Serialize,
+    Deserialize,
 )]
+#[serde(transparent)]
 pub struct BoardId(u16);
 
 impl BoardId {
@@ -234,8 +638,10 @@ impl BoardId {
     }
 
     pub fn decode_response<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<Self, PacketProtocolError> {
-        // TODO - sanity check protocol
         let id = p.payload()?;
+        if id.len() != 2 {
+            return Err(PacketProtocolError::ProtocolViolation);
+        }
         Ok(LittleEndian::read_u16(id).into())
     }
 }
@@ -277,6 +683,19 @@ impl BoardName {
     }
 }
 
+impl Serialize for BoardName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BoardName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(Self(s.into_bytes()))
+    }
+}
+
 impl fmt::Display for BoardName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Ok(s) = self.as_str() {
@@ -308,8 +727,10 @@ impl WritableIdsAnnouncement {
     }
 }
 
-// TODO - allow accumulating
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, From, Into)]
+#[derive(
+    Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, From, Into, Serialize, Deserialize,
+)]
+#[serde(transparent)]
 pub struct IdsAnnouncement(Vec<OwnedMessageId>);
 
 impl IdsAnnouncement {
@@ -331,6 +752,32 @@ impl IdsAnnouncement {
             .collect();
         Ok(Self(ids))
     }
+
+    /// The IDs can span any number of packets, accumulate each one until
+    /// the end-list packet arrives
+    pub fn decode_response_accumulating<T: AsRef<[u8]>>(
+        &mut self,
+        p: &Packet<T>,
+    ) -> Result<(), PacketProtocolError> {
+        let ids = Self::decode_response(p)?;
+        self.0.extend(ids.0);
+        Ok(())
+    }
+
+    /// Checks the accumulated IDs against the count reported in the end-list packet
+    pub fn check_count(
+        &self,
+        end: &WritableIdsAnnouncementEndList,
+    ) -> Result<(), IdsCountMismatchError> {
+        if self.len() != end.0 {
+            Err(IdsCountMismatchError {
+                announced: end.0,
+                received: self.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Into)]
@@ -338,18 +785,20 @@ pub struct WritableIdsAnnouncementEndList(usize);
 
 impl WritableIdsAnnouncementEndList {
     pub fn decode_response<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<Self, PacketProtocolError> {
-        // TODO - sanity check protocol
         let num_ids = p.payload()?;
-        Ok(Self(if p.typ() == MessageType::U8 {
-            num_ids[0] as usize
-        } else {
+        Ok(Self(match (p.typ(), num_ids.len()) {
+            (MessageType::U8, 1) => num_ids[0] as usize,
             // u16
-            LittleEndian::read_u16(num_ids) as usize
+            (typ, 2) if typ != MessageType::U8 => LittleEndian::read_u16(num_ids) as usize,
+            _ => return Err(PacketProtocolError::ProtocolViolation),
         }))
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Into)]
+#[derive(
+    Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, From, Into, Serialize, Deserialize,
+)]
+#[serde(transparent)]
 pub struct TrackedVariables(Vec<Variable>);
 
 impl TrackedVariables {
@@ -383,21 +832,28 @@ impl TrackedVariables {
         &mut self,
         p: &Packet<T>,
     ) -> Result<(), PacketProtocolError> {
-        // TODO - sanity check protocol
-        let id = p.msg_id()?;
-        let typ = p.typ();
-        let data = p.payload()?;
-        self.0.push(Variable {
-            id: OwnedMessageId::from_wire(&id),
-            kind: VariableKind::from_wire(typ, data)?,
-        });
+        self.0.push(Variable::decode_response(p)?);
         Ok(())
     }
 }
 
 #[derive(
-    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Display, UpperHex, From, Into,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Display,
+    UpperHex,
+    From,
+    Into,
+    Serialize,
+    Deserialize,
 )]
+#[serde(transparent)]
 pub struct Heartbeat(u8);
 
 impl Heartbeat {
@@ -420,8 +876,444 @@ impl Heartbeat {
     }
 
     pub fn decode_response<T: AsRef<[u8]>>(p: &Packet<T>) -> Result<Self, PacketProtocolError> {
-        // TODO - sanity check protocol
-        let hb = p.payload()?;
-        Ok(hb[0].into())
+        match p.payload()? {
+            [hb] => Ok((*hb).into()),
+            _ => Err(PacketProtocolError::ProtocolViolation),
+        }
+    }
+}
+
+/// Floats serialized as numbers, except NaN and the infinities which are written as
+/// the strings "NaN", "inf" and "-inf", since JSON would otherwise turn them into `null`
+pub(crate) mod float_serde {
+    use ordered_float::OrderedFloat;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub trait Float: Copy {
+        fn to_f64(self) -> f64;
+        fn from_f64(v: f64) -> Self;
+        fn serialize_number<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error>;
+    }
+
+    impl Float for f32 {
+        fn to_f64(self) -> f64 {
+            f64::from(self)
+        }
+
+        fn from_f64(v: f64) -> Self {
+            v as f32
+        }
+
+        fn serialize_number<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f32(self)
+        }
+    }
+
+    impl Float for f64 {
+        fn to_f64(self) -> f64 {
+            self
+        }
+
+        fn from_f64(v: f64) -> Self {
+            v
+        }
+
+        fn serialize_number<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f64(self)
+        }
+    }
+
+    impl<F: Float + ordered_float::Float> Float for OrderedFloat<F> {
+        fn to_f64(self) -> f64 {
+            self.0.to_f64()
+        }
+
+        fn from_f64(v: f64) -> Self {
+            OrderedFloat(F::from_f64(v))
+        }
+
+        fn serialize_number<S: Serializer>(self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize_number(serializer)
+        }
+    }
+
+    struct Repr<F>(F);
+
+    impl<F: Float> Serialize for Repr<F> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let v = self.0.to_f64();
+            if v.is_nan() {
+                serializer.serialize_str("NaN")
+            } else if v == f64::INFINITY {
+                serializer.serialize_str("inf")
+            } else if v == f64::NEG_INFINITY {
+                serializer.serialize_str("-inf")
+            } else {
+                self.0.serialize_number(serializer)
+            }
+        }
+    }
+
+    impl<'de, F: Float> Deserialize<'de> for Repr<F> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Number {
+                Number(f64),
+                Text(String),
+            }
+            let v = match Number::deserialize(deserializer)? {
+                Number::Number(v) => v,
+                Number::Text(s) => match s.as_str() {
+                    "NaN" => f64::NAN,
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    _ => {
+                        return Err(de::Error::invalid_value(
+                            de::Unexpected::Str(&s),
+                            &"a number, \"NaN\", \"inf\" or \"-inf\"",
+                        ))
+                    }
+                },
+            };
+            Ok(Repr(F::from_f64(v)))
+        }
+    }
+
+    pub mod scalar {
+        use super::{Float, Repr};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<F: Float, S: Serializer>(v: &F, serializer: S) -> Result<S::Ok, S::Error> {
+            Repr(*v).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, F: Float, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<F, D::Error> {
+            Ok(Repr::deserialize(deserializer)?.0)
+        }
+    }
+
+    pub mod array {
+        use super::{Float, Repr};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<F: Float, S: Serializer>(
+            v: &[F],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(v.iter().map(|f| Repr(*f)))
+        }
+
+        pub fn deserialize<'de, F: Float, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<F>, D::Error> {
+            let v = Vec::<Repr<F>>::deserialize(deserializer)?;
+            Ok(v.into_iter().map(|f| f.0).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{collection, num, prelude::*};
+
+    fn round_trip(v_in: &VariableKind) -> VariableKind {
+        let (typ, data) = v_in.to_wire().unwrap();
+        assert_eq!(typ, v_in.message_type());
+        VariableKind::from_wire(typ, &data).unwrap()
+    }
+
+    fn gen_variable_kind() -> impl Strategy<Value = VariableKind> {
+        use VariableKind::*;
+        // Arrays are at least 2 elements, single element arrays decode as scalars
+        let elems = || 2_usize..64;
+        prop_oneof![
+            Just(Callback),
+            collection::vec(num::u8::ANY, 0..64).prop_map(Custom),
+            (13_u8..=0x0F, collection::vec(num::u8::ANY, 0..64)).prop_map(|(t, v)| Unknown(t, v)),
+            num::u8::ANY.prop_map(Byte),
+            collection::vec(num::u8::ANY, elems()).prop_map(ByteArray),
+            num::u8::ANY.prop_map(|c| Char(c.into())),
+            "[ -~]{2,64}".prop_map(CharArray),
+            num::i8::ANY.prop_map(I8),
+            collection::vec(num::i8::ANY, elems()).prop_map(I8Array),
+            num::u8::ANY.prop_map(U8),
+            collection::vec(num::u8::ANY, elems()).prop_map(U8Array),
+            num::i16::ANY.prop_map(I16),
+            collection::vec(num::i16::ANY, elems()).prop_map(I16Array),
+            num::u16::ANY.prop_map(U16),
+            collection::vec(num::u16::ANY, elems()).prop_map(U16Array),
+            num::i32::ANY.prop_map(I32),
+            collection::vec(num::i32::ANY, elems()).prop_map(I32Array),
+            num::u32::ANY.prop_map(U32),
+            collection::vec(num::u32::ANY, elems()).prop_map(U32Array),
+            num::f32::ANY.prop_map(|f| F32(f.into())),
+            collection::vec(num::f32::ANY.prop_map(OrderedFloat), elems()).prop_map(F32Array),
+            num::f64::ANY.prop_map(|f| F64(f.into())),
+            collection::vec(num::f64::ANY.prop_map(OrderedFloat), elems()).prop_map(F64Array),
+        ]
+    }
+
+    #[test]
+    fn wire_encoding() {
+        assert_eq!(
+            VariableKind::U16(200).to_wire(),
+            Ok((MessageType::U16, vec![0xC8, 0x00]))
+        );
+        assert_eq!(
+            VariableKind::I16Array(vec![-1, 2]).to_wire(),
+            Ok((MessageType::I16, vec![0xFF, 0xFF, 0x02, 0x00]))
+        );
+        assert_eq!(
+            VariableKind::CharArray("abc".to_string()).to_wire(),
+            Ok((MessageType::Char, b"abc".to_vec()))
+        );
+        assert_eq!(
+            VariableKind::F32(1.0.into()).to_wire(),
+            Ok((MessageType::F32, vec![0x00, 0x00, 0x80, 0x3F]))
+        );
+        assert_eq!(
+            VariableKind::Callback.to_wire(),
+            Ok((MessageType::Callback, vec![]))
+        );
+        assert_eq!(
+            VariableKind::Unknown(14, vec![1, 2]).to_wire(),
+            Ok((MessageType::Unknown(14), vec![1, 2]))
+        );
+    }
+
+    #[test]
+    fn wire_encoding_errors() {
+        assert_eq!(
+            VariableKind::U16Array(vec![]).to_wire(),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            VariableKind::Char('\u{100}').to_wire(),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            VariableKind::Unknown(u8::from(MessageType::U8), vec![1]).to_wire(),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            VariableKind::Unknown(u8::from(MessageType::OffsetMetadata), vec![]).to_wire(),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            VariableKind::from_wire(MessageType::OffsetMetadata, &[0, 0, 4, 0]),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            VariableKind::ByteArray(vec![0; 1025]).to_wire(),
+            Err(PacketError(packet::Error::InvalidDataLength).into())
+        );
+        assert_eq!(
+            VariableKind::from_wire(MessageType::U32, &[]),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn acked_request_encoding() {
+        let var = Variable {
+            id: OwnedMessageId::new(b"led").unwrap(),
+            kind: VariableKind::U8(1),
+        };
+        let mut buf = vec![0; Packet::<&[u8]>::buffer_len(var.id.len(), 1)];
+        let mut p = Packet::new_unchecked(&mut buf[..]);
+        var.encode_acked_request(5, &mut p).unwrap();
+        assert!(p.response());
+        assert_eq!(p.acknum(), 5);
+        assert_eq!(
+            var.encode_acked_request(0, &mut p),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+        assert_eq!(
+            var.encode_acked_request(MAX_ACKNUM + 1, &mut p),
+            Err(PacketProtocolError::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn numeric_scalars_as_f64() {
+        assert_eq!(VariableKind::I16(-3).as_f64(), Some(-3.0));
+        assert_eq!(VariableKind::F32(OrderedFloat(1.5)).as_f64(), Some(1.5));
+        assert_eq!(VariableKind::U16Array(vec![1, 2]).as_f64(), None);
+        assert_eq!(VariableKind::Char('a').as_f64(), None);
+    }
+
+    #[test]
+    fn parse_text_values() {
+        use MessageType::*;
+        assert_eq!(
+            VariableKind::parse(U16, None, " 250 "),
+            Ok(VariableKind::U16(250))
+        );
+        assert_eq!(
+            VariableKind::parse(U8, None, "0x1F"),
+            Ok(VariableKind::U8(0x1F))
+        );
+        assert_eq!(
+            VariableKind::parse(I8, None, "-128"),
+            Ok(VariableKind::I8(-128))
+        );
+        assert_eq!(
+            VariableKind::parse(F32, None, "1.5"),
+            Ok(VariableKind::F32(OrderedFloat(1.5)))
+        );
+        assert_eq!(
+            VariableKind::parse(I16, None, "[1, -2, 3]"),
+            Ok(VariableKind::I16Array(vec![1, -2, 3]))
+        );
+        assert_eq!(
+            VariableKind::parse(U32, None, "1,2"),
+            Ok(VariableKind::U32Array(vec![1, 2]))
+        );
+        assert_eq!(
+            VariableKind::parse(F64, Some(true), "2.5"),
+            Ok(VariableKind::F64Array(vec![OrderedFloat(2.5)]))
+        );
+        assert_eq!(
+            VariableKind::parse(Char, None, "a"),
+            Ok(VariableKind::Char('a'))
+        );
+        assert_eq!(
+            VariableKind::parse(Char, None, "\"hello\""),
+            Ok(VariableKind::CharArray("hello".to_string()))
+        );
+        assert_eq!(
+            VariableKind::parse(Char, Some(true), "a"),
+            Ok(VariableKind::CharArray("a".to_string()))
+        );
+
+        assert_eq!(
+            VariableKind::parse(U8, None, "256"),
+            Err(ParseVariableError::InvalidValue(U8, "256".to_string()))
+        );
+        assert_eq!(
+            VariableKind::parse(U16, Some(false), "1,2"),
+            Err(ParseVariableError::InvalidValue(U16, "1,2".to_string()))
+        );
+        assert_eq!(
+            VariableKind::parse(I32, None, "[1, x]"),
+            Err(ParseVariableError::InvalidValue(I32, "[1, x]".to_string()))
+        );
+        assert_eq!(
+            VariableKind::parse(Char, Some(false), "\u{100}"),
+            Err(ParseVariableError::InvalidValue(
+                Char,
+                "\u{100}".to_string()
+            ))
+        );
+        assert_eq!(
+            VariableKind::parse(Custom, None, "1"),
+            Err(ParseVariableError::UnsupportedType(Custom))
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_serialize_losslessly() {
+        let kinds = vec![
+            VariableKind::F32(OrderedFloat(f32::NAN)),
+            VariableKind::F64(OrderedFloat(f64::NEG_INFINITY)),
+            VariableKind::F32Array(vec![
+                OrderedFloat(1.1),
+                OrderedFloat(f32::INFINITY),
+                OrderedFloat(f32::NAN),
+            ]),
+            VariableKind::F64Array(vec![OrderedFloat(-0.5), OrderedFloat(f64::NAN)]),
+        ];
+        let json = serde_json::to_string(&kinds).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"type":"F32","value":"NaN"},{"type":"F64","value":"-inf"},{"type":"F32Array","value":[1.1,"inf","NaN"]},{"type":"F64Array","value":[-0.5,"NaN"]}]"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Vec<VariableKind>>(&json).unwrap(),
+            kinds
+        );
+        assert!(serde_json::from_str::<VariableKind>(r#"{"type":"F32","value":"nan?"}"#).is_err());
+    }
+
+    #[test]
+    fn single_element_arrays_decode_as_scalars() {
+        assert_eq!(
+            round_trip(&VariableKind::U16Array(vec![7])),
+            VariableKind::U16(7)
+        );
+        assert_eq!(
+            round_trip(&VariableKind::CharArray("a".to_string())),
+            VariableKind::Char('a')
+        );
+    }
+
+    fn internal_packet(id: MessageId, typ: MessageType, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; Packet::<&[u8]>::buffer_len(id.len(), payload.len())];
+        let mut p = Packet::new_unchecked(&mut buf[..]);
+        p.set_data_length(payload.len() as _).unwrap();
+        p.set_typ(typ);
+        p.set_internal(true);
+        p.set_offset(false);
+        p.set_id_length(id.len() as _).unwrap();
+        p.set_response(false);
+        p.set_acknum(0);
+        p.msg_id_mut().unwrap().copy_from_slice(id.as_bytes());
+        p.payload_mut().unwrap().copy_from_slice(payload);
+        p.set_checksum(p.compute_checksum().unwrap()).unwrap();
+        buf
+    }
+
+    #[test]
+    fn ids_announcement_accumulation() {
+        let list0 = internal_packet(
+            MessageId::INTERNAL_AM_LIST,
+            MessageType::Custom,
+            b"led_blink\0led_state\0",
+        );
+        let list1 = internal_packet(
+            MessageId::INTERNAL_AM_LIST,
+            MessageType::Custom,
+            b"lit_time\0",
+        );
+        let end = internal_packet(MessageId::INTERNAL_AM_END, MessageType::U8, &[3]);
+
+        let mut ids = IdsAnnouncement::default();
+        ids.decode_response_accumulating(&Packet::new(&list0[..]).unwrap())
+            .unwrap();
+        ids.decode_response_accumulating(&Packet::new(&list1[..]).unwrap())
+            .unwrap();
+        assert_eq!(
+            ids.as_slice(),
+            &[
+                OwnedMessageId::from_utf8("led_blink"),
+                OwnedMessageId::from_utf8("led_state"),
+                OwnedMessageId::from_utf8("lit_time"),
+            ]
+        );
+        let end = WritableIdsAnnouncementEndList::decode_response(&Packet::new(&end[..]).unwrap())
+            .unwrap();
+        assert_eq!(ids.check_count(&end), Ok(()));
+
+        let short = IdsAnnouncement::from(ids.as_slice()[..2].to_vec());
+        assert_eq!(
+            short.check_count(&end),
+            Err(IdsCountMismatchError {
+                announced: 3,
+                received: 2
+            })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_variable_kind(v_in in gen_variable_kind()) {
+            let v_out = round_trip(&v_in);
+            assert_eq!(v_in, v_out);
+        }
     }
 }
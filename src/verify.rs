@@ -0,0 +1,141 @@
+use crate::device;
+use crate::opts::{SchemaCommand, SchemaExportOpts, VerifyOpts};
+use crate::output::{Format, Output, Render};
+use electricui_cli::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use thiserror::Error;
+
+pub async fn schema(
+    cmd: SchemaCommand,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match cmd {
+        SchemaCommand::Export(opts) => export(opts, record).await,
+    }
+}
+
+async fn export(
+    opts: SchemaExportOpts,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    // Always taken from the board, a stale cache would defeat the point of a lock file
+    let schema = client.schema(None).await?;
+    let toml = schema.to_toml()?;
+    match &opts.output {
+        Some(path) => fs::write(path, toml)?,
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(toml.as_bytes())?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn verify(
+    opts: VerifyOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let expected = BoardSchema::from_toml(&fs::read_to_string(&opts.schema)?)?;
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    let actual = client.schema(None).await?;
+    let report = VerifyReport {
+        schema: opts.schema.display().to_string(),
+        variables: actual.variables.len(),
+        changes: expected.diff(&actual),
+    };
+    Output::new(format).emit(&report)?;
+    if !report.changes.is_empty() {
+        return Err(SchemaMismatchError(report.changes.len()).into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub schema: String,
+    /// Number of variables the board has
+    pub variables: usize,
+    pub changes: Vec<SchemaChange>,
+}
+
+impl Render for VerifyReport {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "Verified {} variables against '{}', {} difference(s)",
+            self.variables,
+            self.schema,
+            self.changes.len()
+        )?;
+        for change in self.changes.iter() {
+            writeln!(w, "  {change}")?;
+        }
+        Ok(())
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["change", "id", "expected", "actual"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.changes
+            .iter()
+            .map(|c| match c {
+                SchemaChange::BoardId { expected, actual } => vec![
+                    "board_id".to_owned(),
+                    String::new(),
+                    format!("0x{expected:04X}"),
+                    format!("0x{actual:04X}"),
+                ],
+                SchemaChange::BoardName { expected, actual } => vec![
+                    "board_name".to_owned(),
+                    String::new(),
+                    expected.to_string(),
+                    actual.to_string(),
+                ],
+                SchemaChange::Added(var) => vec![
+                    "added".to_owned(),
+                    var.id.to_string(),
+                    String::new(),
+                    var.to_string(),
+                ],
+                SchemaChange::Removed(var) => vec![
+                    "removed".to_owned(),
+                    var.id.to_string(),
+                    var.to_string(),
+                    String::new(),
+                ],
+                SchemaChange::Retyped { expected, actual } => vec![
+                    "retyped".to_owned(),
+                    actual.id.to_string(),
+                    expected.to_string(),
+                    actual.to_string(),
+                ],
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("The board differs from the schema in {0} place(s)")]
+pub struct SchemaMismatchError(pub usize);
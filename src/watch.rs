@@ -0,0 +1,99 @@
+use crate::device;
+use crate::opts::WatchOpts;
+use crate::output::{self, Format, Output, Render};
+use electricui_cli::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+pub async fn watch(
+    opts: WatchOpts,
+    format: Format,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut output = Output::new(format);
+    let dev = device::new(&opts.device).await?;
+    let mut dec_buf = Box::new([0_u8; MAX_PACKET_SIZE]);
+    let mut client = Client::new(
+        dev,
+        &mut dec_buf,
+        device::link_config(&opts.device, record)?,
+    );
+
+    let mut interval = time::interval(opts.interval.into());
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_values: HashMap<OwnedMessageId, VariableKind> = HashMap::new();
+    let mut reassembler = Reassembler::default();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if !opts.listen => {
+                if opts.ids.is_empty() {
+                    debug!("Requesting tracked variables");
+                    client.send_tracked_variables_request().await?;
+                } else {
+                    for id in opts.ids.iter() {
+                        debug!("Requesting variable '{id}'");
+                        client.send_query(id).await?;
+                    }
+                }
+            }
+            pkt = client.recv() => {
+                let pkt = pkt?;
+                if pkt.internal() {
+                    debug!("Ignoring internal packet {pkt}");
+                    continue;
+                }
+                let var = match reassembler.push(&pkt) {
+                    Ok(Some(v)) => v,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Failed to decode variable from packet {pkt}. {e}");
+                        continue;
+                    }
+                };
+                if !opts.ids.is_empty() && !opts.ids.contains(&var.id) {
+                    continue;
+                }
+                let changed = last_values.get(&var.id) != Some(&var.kind);
+                if changed {
+                    info!("Variable '{}' changed", var.id);
+                    last_values.insert(var.id.clone(), var.kind.clone());
+                }
+                if changed || !opts.changes_only {
+                    output.emit(&Sample {
+                        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                        variable: var,
+                    })?;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub variable: Variable,
+}
+
+impl Render for Sample {
+    fn render_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{} {}", self.timestamp, self.variable)
+    }
+
+    fn csv_header(&self) -> Vec<&'static str> {
+        vec!["timestamp", "id", "type", "value"]
+    }
+
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        let mut row = vec![self.timestamp.clone()];
+        row.extend(output::variable_csv_row(&self.variable));
+        vec![row]
+    }
+}